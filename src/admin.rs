@@ -0,0 +1,115 @@
+use std::{sync::Arc, time::Instant};
+
+use axum::{
+    Json, Router,
+    extract::{Query, State},
+    routing::{get, post},
+};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+
+use crate::{
+    dns::DnsHandler,
+    metrics::{DomainCount, DomainStats, Metrics},
+};
+
+#[derive(Clone)]
+struct AdminState {
+    stats: Arc<DomainStats>,
+    handler: DnsHandler,
+    metrics: Arc<Metrics>,
+    started: Instant,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum SortBy {
+    #[default]
+    Total,
+    Blocked,
+}
+
+#[derive(Deserialize)]
+struct TopQuery {
+    limit: Option<usize>,
+    #[serde(default)]
+    by: SortBy,
+}
+
+#[derive(Serialize)]
+struct TopDomain {
+    name: String,
+    #[serde(flatten)]
+    count: DomainCount,
+}
+
+#[derive(Serialize)]
+struct Sizes {
+    uptime_secs: u64,
+    queries: u64,
+    blocklist: usize,
+    allowlist: usize,
+    cached_allow: usize,
+    cached_block: usize,
+    response_cache: usize,
+}
+
+async fn top(
+    State(state): State<AdminState>,
+    Query(query): Query<TopQuery>,
+) -> Json<Vec<TopDomain>> {
+    let mut entries = state.stats.entries();
+    match query.by {
+        SortBy::Total => entries.sort_unstable_by_key(|(_, count)| std::cmp::Reverse(count.total)),
+        SortBy::Blocked => {
+            entries.retain(|(_, count)| count.blocked > 0);
+            entries.sort_unstable_by_key(|(_, count)| std::cmp::Reverse(count.blocked));
+        }
+    }
+    entries.truncate(query.limit.unwrap_or(20));
+    Json(
+        entries
+            .into_iter()
+            .map(|(name, count)| TopDomain { name, count })
+            .collect(),
+    )
+}
+
+async fn reset(State(state): State<AdminState>) {
+    state.stats.clear();
+    log::info!("Reset domain query counters");
+}
+
+async fn stats(State(state): State<AdminState>) -> Json<Sizes> {
+    let (blocklist, allowlist) = state.handler.list_sizes().await;
+    let (cached_allow, cached_block) = state.handler.decision_cache_sizes();
+    Json(Sizes {
+        uptime_secs: state.started.elapsed().as_secs(),
+        queries: state.metrics.queries.get(),
+        blocklist,
+        allowlist,
+        cached_allow,
+        cached_block,
+        response_cache: state.handler.response_cache_size().await,
+    })
+}
+
+pub async fn serve(
+    listener: TcpListener,
+    handler: DnsHandler,
+    metrics: Arc<Metrics>,
+) -> anyhow::Result<()> {
+    let state = AdminState {
+        stats: handler.domain_stats(),
+        handler,
+        metrics,
+        started: Instant::now(),
+    };
+    let router = Router::new()
+        .route("/top", get(top))
+        .route("/reset", post(reset))
+        .route("/stats", get(stats))
+        .with_state(state);
+    axum::serve(listener, router).await?;
+    Ok(())
+}
@@ -0,0 +1,503 @@
+use fxhash::{FxHashMap, FxHashSet};
+use hickory_proto::rr::{RecordType, domain::Label};
+use regex::{Regex, RegexSet};
+use std::net::IpAddr;
+
+pub enum Pattern {
+    Exact(String),
+    Suffix(String),
+    Wildcard(String),
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum BlocklistMatch {
+    Exact,
+    #[default]
+    Suffix,
+}
+
+impl std::str::FromStr for BlocklistMatch {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exact" => Ok(BlocklistMatch::Exact),
+            "suffix" => Ok(BlocklistMatch::Suffix),
+            _ => Err(anyhow::anyhow!("Invalid blocklist match: {}", s)),
+        }
+    }
+}
+
+// The canonical stored form of a domain is lowercase punycode, the same form queries are matched in
+pub fn to_punycode(domain: &str) -> Option<String> {
+    let labels = domain
+        .split('.')
+        .map(|label| {
+            if label.is_ascii() {
+                Some(label.to_ascii_lowercase())
+            } else {
+                Label::from_utf8(label).ok().map(|label| label.to_ascii())
+            }
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(labels.join("."))
+}
+
+// `#` only starts a comment at the beginning of a line or after whitespace, so Adblock rules
+// like `example.com#@#.ad` stay whole and get ignored instead of blocking example.com
+pub fn strip_comment(line: &str) -> &str {
+    let mut after_space = true;
+    for (i, c) in line.char_indices() {
+        if c == '#' && after_space {
+            return line[..i].trim_end();
+        }
+        after_space = c.is_whitespace();
+    }
+    line
+}
+
+impl Pattern {
+    fn parse(domain: &str) -> Option<Self> {
+        let domain = &to_punycode(domain)?;
+        // A bare root would block every name, which is never what a list means
+        if domain.is_empty()
+            || domain == "."
+            || !domain
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '*'))
+        {
+            return None;
+        }
+        let mut domain = domain.to_string();
+        if !domain.ends_with('.') {
+            domain.push('.');
+        }
+        Some(if domain.contains('*') {
+            Self::Wildcard(domain)
+        } else {
+            Self::Suffix(domain)
+        })
+    }
+}
+
+pub struct ListStats {
+    pub lines: usize,
+    pub entries: usize,
+    pub duplicates: usize,
+    pub redundant: usize,
+}
+
+#[derive(Default)]
+pub struct DomainList {
+    match_mode: BlocklistMatch,
+    lines: usize,
+    duplicates: usize,
+    exact: FxHashSet<String>,
+    suffixes: FxHashSet<String>,
+    wildcards: Vec<String>,
+    regexes: Option<RegexSet>,
+    // Entries that only apply to queries of one type
+    typed: FxHashMap<RecordType, DomainList>,
+}
+
+impl DomainList {
+    const HOSTS_ADDRS: [&str; 3] = ["0.0.0.0", "127.0.0.1", "::"];
+    const HOSTS_IGNORED: [&str; 4] = [
+        "localhost",
+        "localhost.localdomain",
+        "local",
+        "broadcasthost",
+    ];
+    pub fn new(match_mode: BlocklistMatch) -> Self {
+        Self {
+            match_mode,
+            ..Default::default()
+        }
+    }
+    fn parse_line(line: &str) -> Vec<Pattern> {
+        let line = line.trim();
+        // `!` starts an Adblock Plus comment and `##` marks a cosmetic (element hiding) rule
+        if line.starts_with('!') || line.contains("##") {
+            return vec![];
+        }
+        let line = strip_comment(line);
+        if line.is_empty() {
+            return vec![];
+        }
+        let mut parts = line.split_whitespace();
+        if parts
+            .next()
+            .is_some_and(|addr| Self::HOSTS_ADDRS.contains(&addr))
+        {
+            return parts
+                .filter(|host| {
+                    !Self::HOSTS_IGNORED.contains(host) && host.parse::<IpAddr>().is_err()
+                })
+                .filter_map(Pattern::parse)
+                .collect();
+        }
+        // `=exact:` pins a single entry to the name itself, whatever BLOCKLIST_MATCH says
+        if let Some(domain) = line.strip_prefix("=exact:") {
+            return match Pattern::parse(domain) {
+                Some(Pattern::Suffix(domain)) => vec![Pattern::Exact(domain)],
+                _ => {
+                    log::debug!("Ignoring unrecognized blocklist line: {line}");
+                    vec![]
+                }
+            };
+        }
+        let pattern = match line.strip_prefix("||") {
+            Some(rule) => rule.strip_suffix('^'),
+            None => Some(line),
+        }
+        .and_then(Pattern::parse);
+        match pattern {
+            Some(pattern) => vec![pattern],
+            None => {
+                log::debug!("Ignoring unrecognized blocklist line: {line}");
+                vec![]
+            }
+        }
+    }
+    // `domain TYPE` scopes an entry to one query type, e.g. `facebook.com HTTPS`
+    fn parse_typed_line(line: &str) -> Option<(Vec<Pattern>, RecordType)> {
+        let fields = strip_comment(line).split_whitespace().collect::<Vec<_>>();
+        let [domain, qtype] = fields.as_slice() else {
+            return None;
+        };
+        if Self::HOSTS_ADDRS.contains(domain) {
+            return None;
+        }
+        let qtype = qtype.to_ascii_uppercase().parse::<RecordType>().ok()?;
+        Some((Self::parse_line(domain), qtype))
+    }
+    pub fn extend_from_str(&mut self, text: &str) -> usize {
+        let mut count = 0;
+        for line in text.lines() {
+            self.lines += 1;
+            if let Some((patterns, qtype)) = Self::parse_typed_line(line) {
+                let list = self
+                    .typed
+                    .entry(qtype)
+                    .or_insert_with(|| DomainList::new(self.match_mode));
+                for pattern in patterns {
+                    if !list.insert(pattern) {
+                        self.duplicates += 1;
+                    }
+                    count += 1;
+                }
+                continue;
+            }
+            for pattern in Self::parse_line(line) {
+                if !self.insert(pattern) {
+                    self.duplicates += 1;
+                }
+                count += 1;
+            }
+        }
+        count
+    }
+    // Returns false when the entry was already present
+    pub fn insert(&mut self, pattern: Pattern) -> bool {
+        match pattern {
+            Pattern::Exact(domain) => self.exact.insert(domain),
+            Pattern::Suffix(domain) if self.match_mode == BlocklistMatch::Exact => {
+                self.exact.insert(domain)
+            }
+            Pattern::Suffix(domain) => self.suffixes.insert(domain),
+            Pattern::Wildcard(glob) => {
+                if self.wildcards.contains(&glob) {
+                    return false;
+                }
+                self.wildcards.push(glob);
+                true
+            }
+        }
+    }
+    // Whether a broader suffix entry already covers everything this entry would match. The most
+    // specific rule wins, so an allow entry more specific than that parent would let the names
+    // through once this entry is gone.
+    fn is_redundant(&self, domain: &str, allowlist: &DomainList) -> bool {
+        let Some(parent) = Self::suffixes_of(domain)
+            .skip(1)
+            .find(|parent| !parent.is_empty() && self.suffixes.contains(*parent))
+        else {
+            return false;
+        };
+        allowlist
+            .most_specific_any_type(domain)
+            .is_none_or(|allowed| allowed < parent.len())
+    }
+    fn most_specific_any_type(&self, name: &str) -> Option<usize> {
+        self.typed
+            .values()
+            .chain([self])
+            .filter_map(|list| list.most_specific(name))
+            .map(|(specificity, _)| specificity)
+            .max()
+    }
+    pub fn stats(&self, allowlist: &DomainList) -> ListStats {
+        ListStats {
+            lines: self.lines,
+            entries: self.len(),
+            duplicates: self.duplicates,
+            redundant: self
+                .exact
+                .iter()
+                .chain(&self.suffixes)
+                .filter(|domain| self.is_redundant(domain, allowlist))
+                .count(),
+        }
+    }
+    // Drops the entries made redundant by a parent, returning how many were removed
+    pub fn compact(&mut self, allowlist: &DomainList) -> usize {
+        let before = self.exact.len() + self.suffixes.len();
+        let redundant = self
+            .exact
+            .iter()
+            .chain(&self.suffixes)
+            .filter(|domain| self.is_redundant(domain, allowlist))
+            .cloned()
+            .collect::<Vec<_>>();
+        for domain in &redundant {
+            self.exact.remove(domain);
+            self.suffixes.remove(domain);
+        }
+        before - self.exact.len() - self.suffixes.len()
+    }
+    // Invalid patterns are skipped with a warning so one bad line doesn't keep the list from loading
+    pub fn set_regexes(&mut self, patterns: Vec<String>) -> anyhow::Result<usize> {
+        let patterns = patterns
+            .into_iter()
+            .filter(|pattern| match Regex::new(pattern) {
+                Ok(_) => true,
+                Err(e) => {
+                    log::warn!("Ignoring invalid regex blocklist entry {pattern}: {e}");
+                    false
+                }
+            })
+            .collect::<Vec<_>>();
+        let count = patterns.len();
+        self.regexes = Some(RegexSet::new(patterns)?);
+        Ok(count)
+    }
+    pub fn len(&self) -> usize {
+        self.exact.len()
+            + self.suffixes.len()
+            + self.wildcards.len()
+            + self.regexes.as_ref().map_or(0, RegexSet::len)
+            + self.typed.values().map(DomainList::len).sum::<usize>()
+    }
+    pub fn has_typed(&self, query_type: RecordType) -> bool {
+        self.typed.contains_key(&query_type)
+    }
+    // The name itself, then each parent up to the root, longest first. An entry matches the name
+    // itself and every name below it, but never part of a label.
+    fn suffixes_of(name: &str) -> impl Iterator<Item = &str> {
+        std::iter::once(name).chain(name.match_indices('.').map(|(i, _)| &name[i + 1..]))
+    }
+    fn glob_match(name: &str, glob: &str) -> bool {
+        let (name, glob) = (name.as_bytes(), glob.as_bytes());
+        let (mut n, mut g) = (0, 0);
+        let mut backtrack = None;
+        while n < name.len() {
+            if g < glob.len() && glob[g] == b'*' {
+                backtrack = Some((g, n));
+                g += 1;
+            } else if g < glob.len() && glob[g] == name[n] {
+                n += 1;
+                g += 1;
+            } else if let Some((star, matched)) = backtrack {
+                g = star + 1;
+                n = matched + 1;
+                backtrack = Some((star, matched + 1));
+            } else {
+                return false;
+            }
+        }
+        glob[g..].iter().all(|&c| c == b'*')
+    }
+    // The matching entry that pins the name down the most, with its specificity: an exact entry
+    // beats any suffix, a longer suffix beats a shorter one, and wildcards count their literal
+    // characters. Regexes are the most expensive and least specific, so they only run when
+    // nothing else matched.
+    pub fn most_specific(&self, name: &str) -> Option<(usize, &str)> {
+        if let Some(exact) = self.exact.get(name) {
+            return Some((exact.len() + 1, exact));
+        }
+        // One hash lookup per label, so huge lists cost no more per query than small ones
+        let suffix = Self::suffixes_of(name)
+            .find_map(|parent| self.suffixes.get(parent))
+            .map(|it| (it.len(), it.as_str()));
+        let wildcard = self
+            .wildcards
+            .iter()
+            .filter(|glob| Self::glob_match(name, glob))
+            .map(|glob| (glob.bytes().filter(|&c| c != b'*').count(), glob.as_str()))
+            .max();
+        suffix.max(wildcard).or_else(|| {
+            let regexes = self.regexes.as_ref()?;
+            let matched = regexes.matches(name).into_iter().next()?;
+            Some((0, regexes.patterns()[matched].as_str()))
+        })
+    }
+    // Entries scoped to the query type compete with the untyped ones on specificity
+    pub fn most_specific_for(&self, name: &str, query_type: RecordType) -> Option<(usize, &str)> {
+        let typed = self
+            .typed
+            .get(&query_type)
+            .and_then(|list| list.most_specific(name));
+        self.most_specific(name).max(typed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(list: &DomainList, name: &str) -> bool {
+        list.most_specific(name).is_some()
+    }
+
+    #[test]
+    fn suffix_match_blocks_subdomains() {
+        let mut list = DomainList::new(BlocklistMatch::Suffix);
+        list.extend_from_str("example.com\n=exact:only.example.net");
+        assert!(matches(&list, "sub.example.com."));
+        assert!(matches(&list, "only.example.net."));
+        assert!(!matches(&list, "sub.only.example.net."));
+    }
+
+    #[test]
+    fn exact_match_leaves_subdomains_alone() {
+        let mut list = DomainList::new(BlocklistMatch::Exact);
+        list.extend_from_str("example.com\n*.ads.example.net");
+        assert!(matches(&list, "example.com."));
+        assert!(!matches(&list, "sub.example.com."));
+        // Wildcards still match by pattern
+        assert!(matches(&list, "x.ads.example.net."));
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_skipped() {
+        let mut list = DomainList::default();
+        let count = list.extend_from_str(
+            "# Title: test list\n\n   \n  # indented\n! adblock comment\nexample.com # tracker\n\
+         ads.example.net\t# tab before the comment",
+        );
+        assert_eq!(count, 2);
+        assert!(matches(&list, "example.com."));
+        assert!(matches(&list, "ads.example.net."));
+        assert!(!matches(&list, "tracker."));
+    }
+
+    #[test]
+    fn adblock_cosmetic_rules_block_nothing() {
+        let mut list = DomainList::default();
+        let count = list.extend_from_str(
+            "example.com##.banner\nexample.com#@#.ad\nexample.com#?#div:has(> .ad)\n\
+         example.com#$#abort-on-property-read ads\nexample.com#%#//scriptlet('noop')",
+        );
+        assert_eq!(count, 0);
+        assert!(!matches(&list, "example.com."));
+    }
+
+    #[test]
+    fn entries_under_a_listed_parent_are_redundant() {
+        let mut list = DomainList::default();
+        list.extend_from_str(
+            "example.com\nads.example.com\nexample.com\nx.ads.example.com\nother.net",
+        );
+        let stats = list.stats(&DomainList::default());
+        assert_eq!(stats.lines, 5);
+        assert_eq!(stats.entries, 4);
+        assert_eq!(stats.duplicates, 1);
+        assert_eq!(stats.redundant, 2);
+        assert_eq!(list.compact(&DomainList::default()), 2);
+        assert_eq!(list.len(), 2);
+        assert!(matches(&list, "x.ads.example.com."));
+    }
+
+    #[test]
+    fn entries_under_a_more_specific_allow_are_kept() {
+        let mut list = DomainList::default();
+        list.extend_from_str("example.com\nads.x.example.com\ntracker.example.com");
+        let mut allowlist = DomainList::default();
+        allowlist.extend_from_str("x.example.com");
+        // Without ads.x.example.com, the allow entry would be the most specific rule for it
+        assert_eq!(list.stats(&allowlist).redundant, 1);
+        assert_eq!(list.compact(&allowlist), 1);
+        assert_eq!(list.len(), 2);
+        assert_eq!(
+            list.most_specific("tracker.example.com."),
+            Some((12, "example.com."))
+        );
+        let (blocked, _) = list.most_specific("ads.x.example.com.").unwrap();
+        let (allowed, _) = allowlist.most_specific("ads.x.example.com.").unwrap();
+        assert!(blocked > allowed);
+    }
+
+    #[test]
+    fn longest_suffix_wins() {
+        let mut list = DomainList::default();
+        list.extend_from_str("example.com\nads.example.com\nx.ads.example.com");
+        assert_eq!(
+            list.most_specific("a.ads.example.com."),
+            Some((16, "ads.example.com."))
+        );
+        assert_eq!(
+            list.most_specific("x.ads.example.com."),
+            Some((18, "x.ads.example.com."))
+        );
+        assert_eq!(list.most_specific("com."), None);
+    }
+
+    #[test]
+    fn suffix_entry_edge_cases() {
+        let mut list = DomainList::default();
+        list.extend_from_str("example.com\n.\nlonger.name.example.org");
+        // The entry itself matches, not just its subdomains
+        assert!(matches(&list, "example.com."));
+        assert!(!matches(&list, "badexample.com."));
+        // The root entry is ignored rather than blocking everything
+        assert_eq!(list.len(), 2);
+        assert!(!matches(&list, "example.net."));
+        assert!(!matches(&list, "."));
+        // An entry longer than the name can't match it
+        assert!(!matches(&list, "example.org."));
+    }
+
+    #[test]
+    fn typed_entry_matches_only_its_type() {
+        let mut list = DomainList::default();
+        list.extend_from_str("facebook.com HTTPS\nads.example A # tracker\n0.0.0.0 a");
+        // The hosts line is an untyped entry for `a`, not an A entry for 0.0.0.0
+        assert_eq!(list.len(), 3);
+        assert!(
+            list.most_specific_for("www.facebook.com.", RecordType::HTTPS)
+                .is_some()
+        );
+        assert!(
+            list.most_specific_for("www.facebook.com.", RecordType::A)
+                .is_none()
+        );
+        assert!(
+            list.most_specific_for("ads.example.", RecordType::A)
+                .is_some()
+        );
+        assert!(
+            list.most_specific_for("ads.example.", RecordType::MX)
+                .is_none()
+        );
+        assert!(!matches(&list, "facebook.com."));
+    }
+
+    #[test]
+    fn unicode_entries_match_their_punycode_form() {
+        let mut list = DomainList::default();
+        list.extend_from_str("bücher.example");
+        assert!(matches(&list, "xn--bcher-kva.example."));
+        assert!(matches(&list, "shop.xn--bcher-kva.example."));
+        assert!(!matches(&list, "bucher.example."));
+    }
+}
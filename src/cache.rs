@@ -0,0 +1,559 @@
+use fxhash::{FxBuildHasher, FxHashMap};
+use hickory_proto::{
+    op::ResponseCode,
+    rr::{
+        DNSClass, Name, RData, Record, RecordType,
+        rdata::{
+            HTTPS, SVCB,
+            svcb::{Mandatory, SvcParamKey, SvcParamValue},
+        },
+    },
+    serialize::binary::{BinDecodable, BinEncodable},
+    xfer::DnsResponse,
+};
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Borrow,
+    hash::Hash,
+    num::NonZeroUsize,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicU32, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::RwLock;
+
+pub type CacheKey = (Name, DNSClass, RecordType);
+
+#[derive(Clone)]
+pub struct Answer {
+    pub response_code: ResponseCode,
+    pub recursion_available: bool,
+    pub authentic_data: bool,
+    pub answers: Vec<Record>,
+    pub authorities: Vec<Record>,
+    pub additionals: Vec<Record>,
+}
+
+impl From<DnsResponse> for Answer {
+    fn from(response: DnsResponse) -> Self {
+        Self {
+            response_code: response.response_code(),
+            recursion_available: response.recursion_available(),
+            // Only set when every answer was validated locally, the upstream's AD bit is not trusted
+            authentic_data: !response.answers().is_empty()
+                && response
+                    .answers()
+                    .iter()
+                    .all(|record| record.proof().is_secure()),
+            answers: response.answers().to_vec(),
+            authorities: response.authorities().to_vec(),
+            additionals: response.additionals().to_vec(),
+        }
+    }
+}
+
+impl Answer {
+    pub fn negative_ttl(&self) -> Option<u32> {
+        self.authorities
+            .iter()
+            .find_map(|record| match record.data() {
+                RData::SOA(soa) => Some(record.ttl().min(soa.minimum())),
+                _ => None,
+            })
+    }
+    fn ttl(&self) -> Option<u32> {
+        let ttl = self.answers.iter().map(Record::ttl).min();
+        // RFC 2308: NXDOMAIN/NODATA are cached for the SOA minimum, and not at all without an SOA
+        if self.response_code == ResponseCode::NXDomain || self.answers.is_empty() {
+            return self
+                .negative_ttl()
+                .map(|negative| ttl.map_or(negative, |ttl| ttl.min(negative)));
+        }
+        ttl
+    }
+    fn with_record_ttl(&self, ttl: impl Fn(&Record) -> u32) -> Self {
+        let mut answer = self.clone();
+        for record in answer
+            .answers
+            .iter_mut()
+            .chain(answer.authorities.iter_mut())
+            .chain(answer.additionals.iter_mut())
+        {
+            record.set_ttl(ttl(record));
+        }
+        answer
+    }
+    fn with_ttl(&self, ttl: impl Fn(u32) -> u32) -> Self {
+        self.with_record_ttl(|record| ttl(record.ttl()))
+    }
+    // A range set for a record's own type replaces the general one, so an SOA can be kept short
+    // while NS records are held for hours
+    pub fn with_clamped_ttl(
+        &self,
+        range: Option<(u32, u32)>,
+        type_ranges: &FxHashMap<RecordType, (u32, u32)>,
+    ) -> Self {
+        self.with_record_ttl(|record| {
+            match type_ranges.get(&record.record_type()).copied().or(range) {
+                Some((min, max)) => record.ttl().clamp(min, max),
+                None => record.ttl(),
+            }
+        })
+    }
+    // Leaves the rest of HTTPS/SVCB records alone, ech has to go from their mandatory keys as well
+    pub fn without_ech(mut self) -> Self {
+        for record in self.answers.iter_mut().chain(self.additionals.iter_mut()) {
+            let rdata = match record.data() {
+                RData::SVCB(svcb) => RData::SVCB(Self::strip_ech(svcb)),
+                RData::HTTPS(HTTPS(svcb)) => RData::HTTPS(HTTPS(Self::strip_ech(svcb))),
+                _ => continue,
+            };
+            // The signatures no longer cover what is sent
+            if rdata != *record.data() {
+                self.authentic_data = false;
+                record.set_data(rdata);
+            }
+        }
+        self
+    }
+    fn strip_ech(svcb: &SVCB) -> SVCB {
+        let params = svcb
+            .svc_params()
+            .iter()
+            .filter(|(key, _)| *key != SvcParamKey::EchConfigList)
+            .map(|(key, value)| match value {
+                SvcParamValue::Mandatory(Mandatory(keys)) => (
+                    *key,
+                    SvcParamValue::Mandatory(Mandatory(
+                        keys.iter()
+                            .copied()
+                            .filter(|key| *key != SvcParamKey::EchConfigList)
+                            .collect(),
+                    )),
+                ),
+                _ => (*key, value.clone()),
+            })
+            .collect();
+        SVCB::new(svcb.svc_priority(), svcb.target_name().clone(), params)
+    }
+    fn with_elapsed(&self, elapsed: Duration) -> Self {
+        let elapsed = elapsed.as_secs().min(u32::MAX as u64) as u32;
+        self.with_ttl(|ttl| ttl.saturating_sub(elapsed))
+    }
+}
+
+// Records are kept in wire format, which hickory already knows how to round-trip
+#[derive(Serialize, Deserialize)]
+struct SnapshotEntry {
+    name: String,
+    class: u16,
+    qtype: u16,
+    response_code: u16,
+    recursion_available: bool,
+    authentic_data: bool,
+    answers: Vec<Vec<u8>>,
+    authorities: Vec<Vec<u8>>,
+    additionals: Vec<Vec<u8>>,
+    // Seconds since the Unix epoch, an Instant means nothing to the next process
+    expires: u64,
+}
+
+struct CacheEntry {
+    answer: Answer,
+    inserted: Instant,
+    expires: Instant,
+    hits: AtomicU32,
+    prefetching: AtomicBool,
+}
+
+pub struct ResponseCache {
+    entries: RwLock<FxHashMap<CacheKey, CacheEntry>>,
+    max_entries: usize,
+    // How long past expiry an entry is kept around for serve-stale
+    stale_window: Duration,
+    // How long past expiry an entry is still answered while it is refreshed in the background
+    revalidate_window: Duration,
+    prefetch: bool,
+}
+
+impl ResponseCache {
+    // RFC 8767 recommends 30 seconds so clients come back soon after the upstream recovers
+    const STALE_TTL: u32 = 30;
+    const PREFETCH_MIN_HITS: u32 = 3;
+    // Refresh once less than a tenth of the original TTL is left
+    const PREFETCH_REMAINING_DIVISOR: u32 = 10;
+    // Short, so clients pick up the refreshed answer soon after it lands
+    const REVALIDATE_TTL: u32 = 5;
+    pub fn new(
+        max_entries: usize,
+        stale_window: Duration,
+        revalidate_window: Duration,
+        prefetch: bool,
+    ) -> Self {
+        Self {
+            entries: RwLock::new(FxHashMap::default()),
+            max_entries,
+            stale_window,
+            revalidate_window,
+            prefetch,
+        }
+    }
+    // The flag asks the caller to refresh the entry, and is handed out once per cached answer
+    pub async fn get(&self, key: &CacheKey) -> Option<(Answer, bool)> {
+        let entries = self.entries.read().await;
+        let entry = entries.get(key)?;
+        let now = Instant::now();
+        if entry.expires <= now {
+            if entry.expires + self.revalidate_window <= now {
+                return None;
+            }
+            log::debug!("Serving expired answer for {} while revalidating", key.0);
+            let refresh = !entry.prefetching.swap(true, Ordering::Relaxed);
+            return Some((entry.answer.with_ttl(|_| Self::REVALIDATE_TTL), refresh));
+        }
+        let hits = entry.hits.fetch_add(1, Ordering::Relaxed) + 1;
+        let prefetch = self.prefetch
+            && hits >= Self::PREFETCH_MIN_HITS
+            && entry.expires - now
+                < (entry.expires - entry.inserted) / Self::PREFETCH_REMAINING_DIVISOR
+            && !entry.prefetching.swap(true, Ordering::Relaxed);
+        Some((entry.answer.with_elapsed(now - entry.inserted), prefetch))
+    }
+    pub async fn len(&self) -> usize {
+        self.entries.read().await.len()
+    }
+    pub async fn contains(&self, key: &CacheKey) -> bool {
+        self.entries
+            .read()
+            .await
+            .get(key)
+            .is_some_and(|entry| entry.expires > Instant::now())
+    }
+    pub async fn get_stale(&self, key: &CacheKey) -> Option<Answer> {
+        let entries = self.entries.read().await;
+        let entry = entries.get(key)?;
+        if entry.expires + self.stale_window <= Instant::now() {
+            return None;
+        }
+        Some(entry.answer.with_ttl(|_| Self::STALE_TTL))
+    }
+    pub async fn insert(&self, key: CacheKey, answer: Answer) {
+        if self.max_entries == 0
+            || !matches!(
+                answer.response_code,
+                ResponseCode::NoError | ResponseCode::NXDomain
+            )
+        {
+            return;
+        }
+        let Some(ttl) = answer.ttl().filter(|ttl| *ttl > 0) else {
+            return;
+        };
+        let now = Instant::now();
+        let mut entries = self.entries.write().await;
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            let keep = self.stale_window.max(self.revalidate_window);
+            entries.retain(|_, entry| entry.expires + keep > now);
+            if entries.len() >= self.max_entries {
+                let soonest = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.expires)
+                    .map(|(key, _)| key.clone());
+                if let Some(soonest) = soonest {
+                    entries.remove(&soonest);
+                }
+            }
+        }
+        entries.insert(
+            key,
+            CacheEntry {
+                answer,
+                inserted: now,
+                expires: now + Duration::from_secs(ttl as u64),
+                hits: AtomicU32::new(0),
+                prefetching: AtomicBool::new(false),
+            },
+        );
+    }
+    fn unix_now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs())
+    }
+    pub async fn snapshot(&self) -> anyhow::Result<Vec<u8>> {
+        let encode = |records: &[Record]| {
+            records
+                .iter()
+                .map(|record| record.to_bytes())
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let (now, unix_now) = (Instant::now(), Self::unix_now());
+        let entries = self.entries.read().await;
+        let mut snapshot = Vec::with_capacity(entries.len());
+        for ((name, class, qtype), entry) in entries.iter() {
+            if entry.expires <= now {
+                continue;
+            }
+            let answer = entry.answer.with_elapsed(now - entry.inserted);
+            snapshot.push(SnapshotEntry {
+                name: name.to_ascii(),
+                class: (*class).into(),
+                qtype: (*qtype).into(),
+                response_code: answer.response_code.into(),
+                recursion_available: answer.recursion_available,
+                authentic_data: answer.authentic_data,
+                answers: encode(&answer.answers)?,
+                authorities: encode(&answer.authorities)?,
+                additionals: encode(&answer.additionals)?,
+                expires: unix_now + (entry.expires - now).as_secs(),
+            });
+        }
+        Ok(postcard::to_stdvec(&snapshot)?)
+    }
+    // Entries that expired while ndns was down are dropped, the rest count down from where they were
+    pub async fn restore(&self, bytes: &[u8]) -> anyhow::Result<usize> {
+        let decode = |records: &[Vec<u8>]| {
+            records
+                .iter()
+                .map(|record| Record::from_bytes(record))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let snapshot = postcard::from_bytes::<Vec<SnapshotEntry>>(bytes)?;
+        let (now, unix_now) = (Instant::now(), Self::unix_now());
+        let mut entries = self.entries.write().await;
+        let mut restored = 0;
+        for entry in snapshot {
+            if entry.expires <= unix_now || entries.len() >= self.max_entries {
+                continue;
+            }
+            let remaining = (entry.expires - unix_now).min(u32::MAX as u64) as u32;
+            let answer = Answer {
+                response_code: entry.response_code.into(),
+                recursion_available: entry.recursion_available,
+                authentic_data: entry.authentic_data,
+                answers: decode(&entry.answers)?,
+                authorities: decode(&entry.authorities)?,
+                additionals: decode(&entry.additionals)?,
+            };
+            entries.insert(
+                (
+                    Name::from_ascii(&entry.name)?,
+                    entry.class.into(),
+                    entry.qtype.into(),
+                ),
+                CacheEntry {
+                    answer: answer.with_ttl(|ttl| ttl.min(remaining)),
+                    inserted: now,
+                    expires: now + Duration::from_secs(remaining as u64),
+                    hits: AtomicU32::new(0),
+                    prefetching: AtomicBool::new(false),
+                },
+            );
+            restored += 1;
+        }
+        Ok(restored)
+    }
+}
+
+pub struct ShardedLru<K, V> {
+    shards: Vec<Mutex<LruCache<K, V, FxBuildHasher>>>,
+}
+
+pub type DecisionCache = ShardedLru<String, ()>;
+// Blocked names remember the entry that matched them
+pub type BlockCache = ShardedLru<String, String>;
+
+impl<K: Hash + Eq, V> ShardedLru<K, V> {
+    const SHARDS: usize = 16;
+    pub fn new(max_entries: usize) -> Self {
+        // Each shard evicts on its own, so the total stays within max_entries rounded up to the shard count
+        let shards = match NonZeroUsize::new(max_entries.div_ceil(Self::SHARDS)) {
+            Some(capacity) => (0..Self::SHARDS)
+                .map(|_| Mutex::new(LruCache::with_hasher(capacity, FxBuildHasher::default())))
+                .collect(),
+            None => vec![],
+        };
+        Self { shards }
+    }
+    fn shard<Q: Hash + ?Sized>(&self, key: &Q) -> Option<&Mutex<LruCache<K, V, FxBuildHasher>>> {
+        if self.shards.is_empty() {
+            return None;
+        }
+        Some(&self.shards[fxhash::hash(key) % self.shards.len()])
+    }
+    pub fn contains<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shard(key)
+            .is_some_and(|shard| shard.lock().unwrap().get(key).is_some())
+    }
+    pub fn update<Q, R>(&self, key: &Q, f: impl FnOnce(&mut V) -> R) -> Option<R>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+        V: Default,
+    {
+        let mut shard = self.shard(key)?.lock().unwrap();
+        Some(match shard.get_mut(key) {
+            Some(value) => f(value),
+            None => {
+                let mut value = V::default();
+                let result = f(&mut value);
+                shard.put(key.to_owned(), value);
+                result
+            }
+        })
+    }
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        self.shard(key)?.lock().unwrap().get(key).cloned()
+    }
+    pub fn entries(&self) -> Vec<(K, V)>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().len())
+            .sum()
+    }
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            shard.lock().unwrap().clear();
+        }
+    }
+}
+
+impl<V> ShardedLru<String, V> {
+    pub fn insert(&self, name: &str, value: V) -> bool {
+        let Some(shard) = self.shard(name) else {
+            return false;
+        };
+        let mut shard = shard.lock().unwrap();
+        if shard.get(name).is_some() {
+            return false;
+        }
+        shard.put(name.to_string(), value);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::rr::rdata::{A, AAAA};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    const ANSWER: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+    #[tokio::test]
+    async fn cache_snapshot_round_trips() {
+        let key = (
+            Name::from_ascii("cached.example.").unwrap(),
+            DNSClass::IN,
+            RecordType::A,
+        );
+        let answer = Answer {
+            response_code: ResponseCode::NoError,
+            recursion_available: true,
+            authentic_data: false,
+            answers: vec![Record::from_rdata(key.0.clone(), 300, RData::A(A(ANSWER)))],
+            authorities: vec![],
+            additionals: vec![],
+        };
+        let cache = ResponseCache::new(10, Duration::ZERO, Duration::ZERO, false);
+        cache.insert(key.clone(), answer).await;
+        let snapshot = cache.snapshot().await.unwrap();
+
+        let restored = ResponseCache::new(10, Duration::ZERO, Duration::ZERO, false);
+        assert_eq!(restored.restore(&snapshot).await.unwrap(), 1);
+        let (answer, _) = restored.get(&key).await.unwrap();
+        assert_eq!(answer.answers[0].data(), &RData::A(A(ANSWER)));
+        assert!(answer.answers[0].ttl() <= 300 && answer.answers[0].ttl() > 290);
+        assert!(answer.recursion_available);
+    }
+
+    #[test]
+    fn decision_cache_stays_within_its_cap() {
+        let cache = DecisionCache::new(32);
+        std::thread::scope(|scope| {
+            for thread in 0..8 {
+                let cache = &cache;
+                scope.spawn(move || {
+                    for i in 0..1000 {
+                        cache.insert(&format!("name{i}.thread{thread}.example."), ());
+                    }
+                });
+            }
+        });
+        // 8000 names went in, every shard filled up and then evicted
+        assert_eq!(cache.len(), 32);
+    }
+
+    #[tokio::test]
+    async fn a_and_aaaa_of_one_name_expire_independently() {
+        let name = Name::from_ascii("both.example.").unwrap();
+        let answer = |ttl, rdata| Answer {
+            response_code: ResponseCode::NoError,
+            recursion_available: true,
+            authentic_data: false,
+            answers: vec![Record::from_rdata(name.clone(), ttl, rdata)],
+            authorities: vec![],
+            additionals: vec![],
+        };
+        let a = (name.clone(), DNSClass::IN, RecordType::A);
+        let aaaa = (name.clone(), DNSClass::IN, RecordType::AAAA);
+        let cache = ResponseCache::new(10, Duration::ZERO, Duration::ZERO, false);
+        cache
+            .insert(a.clone(), answer(1, RData::A(A(ANSWER))))
+            .await;
+        cache
+            .insert(
+                aaaa.clone(),
+                answer(300, RData::AAAA(AAAA(Ipv6Addr::LOCALHOST))),
+            )
+            .await;
+        assert_eq!(cache.len().await, 2);
+        let (cached, _) = cache.get(&aaaa).await.unwrap();
+        assert_eq!(
+            cached.answers[0].data(),
+            &RData::AAAA(AAAA(Ipv6Addr::LOCALHOST))
+        );
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert!(cache.get(&a).await.is_none());
+        let (cached, _) = cache.get(&aaaa).await.unwrap();
+        assert!(cached.answers[0].ttl() > 290);
+    }
+
+    #[test]
+    fn zero_capacity_caches_hold_nothing() {
+        let cache = DecisionCache::new(0);
+        assert!(!cache.insert("example.com.", ()));
+        assert!(!cache.contains("example.com."));
+        assert_eq!(cache.len(), 0);
+    }
+}
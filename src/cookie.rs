@@ -0,0 +1,86 @@
+use std::{
+    hash::{BuildHasher, Hasher, RandomState},
+    net::IpAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// RFC 7873 DNS cookies with RFC 9018 style server cookies, keyed by a secret that lives as long as
+// the process, so cookies handed out before a restart are simply replaced
+pub struct CookieSecret(RandomState);
+
+impl CookieSecret {
+    const CLIENT_LEN: usize = 8;
+    const VERSION: u8 = 1;
+    const MAX_AGE_SECS: u32 = 3600;
+
+    pub fn new() -> Self {
+        Self(RandomState::new())
+    }
+    fn now() -> u32 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs() as u32)
+    }
+    fn server_cookie(&self, client_cookie: &[u8], timestamp: u32, client: IpAddr) -> Vec<u8> {
+        let mut cookie = vec![Self::VERSION, 0, 0, 0];
+        cookie.extend_from_slice(&timestamp.to_be_bytes());
+        let mut hasher = self.0.build_hasher();
+        hasher.write(client_cookie);
+        hasher.write(&cookie);
+        match client {
+            IpAddr::V4(ip) => hasher.write(&ip.octets()),
+            IpAddr::V6(ip) => hasher.write(&ip.octets()),
+        }
+        cookie.extend_from_slice(&hasher.finish().to_be_bytes());
+        cookie
+    }
+    fn is_valid(&self, option: &[u8], client: IpAddr) -> bool {
+        let (client_cookie, server_cookie) = option.split_at(Self::CLIENT_LEN);
+        let Some(timestamp) = server_cookie.get(4..8) else {
+            return false;
+        };
+        let timestamp = u32::from_be_bytes(timestamp.try_into().unwrap());
+        Self::now().wrapping_sub(timestamp) <= Self::MAX_AGE_SECS
+            && self.server_cookie(client_cookie, timestamp, client) == server_cookie
+    }
+    // The COOKIE option to answer with, or None when the client's option is malformed (FORMERR)
+    pub fn respond(&self, option: &[u8], client: IpAddr) -> Option<Vec<u8>> {
+        if option.len() != Self::CLIENT_LEN && !(16..=40).contains(&option.len()) {
+            return None;
+        }
+        if option.len() > Self::CLIENT_LEN && !self.is_valid(option, client) {
+            log::debug!("Replacing stale or unknown server cookie from {client}");
+        }
+        let client_cookie = &option[..Self::CLIENT_LEN];
+        let mut cookie = client_cookie.to_vec();
+        cookie.extend(self.server_cookie(client_cookie, Self::now(), client));
+        Some(cookie)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_cookies_are_bound_to_the_client() {
+        let secret = CookieSecret::new();
+        let client = "192.0.2.1".parse().unwrap();
+        let cookie = secret.respond(&[7; 8], client).unwrap();
+        assert_eq!(cookie.len(), 24);
+        assert_eq!(&cookie[..8], &[7; 8]);
+        assert!(secret.is_valid(&cookie, client));
+        assert!(!secret.is_valid(&cookie, "192.0.2.2".parse().unwrap()));
+        assert!(!CookieSecret::new().is_valid(&cookie, client));
+    }
+
+    #[test]
+    fn malformed_cookie_options_are_rejected() {
+        let secret = CookieSecret::new();
+        let client = "192.0.2.1".parse().unwrap();
+        for len in [0, 7, 9, 15, 41] {
+            assert!(secret.respond(&vec![0; len], client).is_none(), "{len}");
+        }
+        assert!(secret.respond(&[0; 16], client).is_some());
+    }
+}
@@ -1,72 +1,1122 @@
-use fxhash::FxHashSet;
+use crate::{
+    blocklist::DomainList,
+    cache::{Answer, BlockCache, CacheKey, DecisionCache, ResponseCache},
+    cookie::CookieSecret,
+    local::{LocalAnswer, LocalRecords},
+    metrics::{DomainStats, Metrics},
+    querylog::{QueryLog, QueryLogEntry},
+    ratelimit::{RateLimit, RateLimitAction, RateLimiter},
+};
+use futures_util::{
+    FutureExt, StreamExt,
+    future::{BoxFuture, Shared},
+    stream::FuturesUnordered,
+};
+use fxhash::FxHashMap;
 use hickory_client::client::{Client, ClientHandle};
 use hickory_proto::{
-    op::{Edns, Header, MessageType, OpCode, ResponseCode},
-    rr::{DNSClass, IntoName, Name, Record, RecordType},
-    xfer::DnsResponse,
+    ProtoError, ProtoErrorKind,
+    dnssec::{DnssecDnsHandle, TrustAnchors},
+    op::{Edns, Header, Message, MessageType, OpCode, Query, ResponseCode},
+    rr::{
+        DNSClass, IntoName, Name, RData, Record, RecordType,
+        rdata::{
+            A, AAAA, CNAME, SOA, TXT,
+            opt::{ClientSubnet, EdnsCode, EdnsOption},
+        },
+    },
+    xfer::{DnsHandle, DnsRequest, DnsRequestOptions, DnsResponse, FirstAnswer, Protocol},
 };
 use hickory_server::{
     authority::{MessageResponse, MessageResponseBuilder},
     server::{Request, RequestHandler, ResponseHandler, ResponseInfo},
 };
-use std::sync::Arc;
-use tokio::sync::{Mutex, RwLock};
+use ipnet::{IpNet, Ipv6Net};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::{Mutex, RwLock, Semaphore};
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BlockMode {
+    NxDomain,
+    Refused,
+    Sinkhole,
+}
+
+impl std::str::FromStr for BlockMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nxdomain" => Ok(BlockMode::NxDomain),
+            "refused" => Ok(BlockMode::Refused),
+            "sinkhole" => Ok(BlockMode::Sinkhole),
+            _ => Err(anyhow::anyhow!("Invalid block mode: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BlocklistMode {
+    Enforce,
+    // Evaluate and log matches but answer every query normally
+    Monitor,
+}
+
+impl std::str::FromStr for BlocklistMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "enforce" => Ok(BlocklistMode::Enforce),
+            "monitor" => Ok(BlocklistMode::Monitor),
+            _ => Err(anyhow::anyhow!("Invalid blocklist mode: {}", s)),
+        }
+    }
+}
+
+// What requests that decode but can't be answered, like ones without exactly one question, get
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum MalformedPolicy {
+    Drop,
+    FormErr,
+    ServFail,
+}
+
+impl std::str::FromStr for MalformedPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(MalformedPolicy::Drop),
+            "formerr" => Ok(MalformedPolicy::FormErr),
+            "servfail" => Ok(MalformedPolicy::ServFail),
+            _ => Err(anyhow::anyhow!("Invalid malformed policy: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamStrategy {
+    Failover,
+    RoundRobin,
+    Race,
+}
+
+impl std::str::FromStr for UpstreamStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "failover" => Ok(UpstreamStrategy::Failover),
+            "round_robin" => Ok(UpstreamStrategy::RoundRobin),
+            "race" => Ok(UpstreamStrategy::Race),
+            _ => Err(anyhow::anyhow!("Invalid upstream strategy: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum AddressFamily {
+    Ipv4,
+    Ipv6,
+    Both,
+}
+
+impl std::str::FromStr for AddressFamily {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ipv4" => Ok(AddressFamily::Ipv4),
+            "ipv6" => Ok(AddressFamily::Ipv6),
+            "both" => Ok(AddressFamily::Both),
+            _ => Err(anyhow::anyhow!("Invalid address family: {}", s)),
+        }
+    }
+}
+
+impl AddressFamily {
+    fn allows(self, record_type: RecordType) -> bool {
+        !matches!(
+            (self, record_type),
+            (AddressFamily::Ipv4, RecordType::AAAA) | (AddressFamily::Ipv6, RecordType::A)
+        )
+    }
+}
+
+// Failures we detect ourselves, as opposed to the ProtoErrors hickory returns
+#[derive(Debug)]
+enum UpstreamError {
+    Timeout(String),
+    Refused(String),
+    Saturated(usize),
+}
+
+impl std::fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpstreamError::Timeout(addr) => write!(f, "Upstream {addr} timed out"),
+            UpstreamError::Refused(addr) => write!(f, "Upstream {addr} refused the query"),
+            UpstreamError::Saturated(limit) => {
+                write!(f, "All {limit} upstream query slots stayed busy")
+            }
+        }
+    }
+}
+
+impl std::error::Error for UpstreamError {}
+
+// Every query coalesced onto one upstream request gets the same failure
+#[derive(Debug)]
+struct SharedError(Arc<anyhow::Error>);
+
+impl std::fmt::Display for SharedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for SharedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some((*self.0).as_ref())
+    }
+}
+
+// Which list has the final say on a name, before DEFAULT_DENY is taken into account
+enum Decision {
+    Blocked(String),
+    Allowed,
+    Unlisted,
+}
+
+type InflightQuery = Shared<BoxFuture<'static, Result<Answer, Arc<anyhow::Error>>>>;
+
+// Why a query couldn't be answered normally, which also decides the rcode it gets
+#[derive(Debug, thiserror::Error)]
+pub enum DnsError {
+    #[error("{0:#}")]
+    Timeout(anyhow::Error),
+    #[error("{0:#}")]
+    Transport(anyhow::Error),
+    #[error("{0:#}")]
+    Refused(anyhow::Error),
+    #[error("{0:#}")]
+    Saturated(anyhow::Error),
+    // Undecodable answers and ones that failed DNSSEC validation
+    #[error("Invalid upstream answer: {0:#}")]
+    Invalid(anyhow::Error),
+    #[error("Malformed request: {0}")]
+    Malformed(ProtoError),
+    #[error("Failed to send the response: {0}")]
+    Send(#[from] std::io::Error),
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
+
+impl DnsError {
+    // Looks through the whole chain, since coalesced queries get the failure wrapped in a SharedError
+    fn upstream(e: anyhow::Error) -> Self {
+        let variant = e
+            .chain()
+            .find_map(|cause| -> Option<fn(anyhow::Error) -> Self> {
+                if let Some(e) = cause.downcast_ref::<UpstreamError>() {
+                    return Some(match e {
+                        UpstreamError::Timeout(_) => DnsError::Timeout,
+                        UpstreamError::Refused(_) => DnsError::Refused,
+                        UpstreamError::Saturated(_) => DnsError::Saturated,
+                    });
+                }
+                Some(match cause.downcast_ref::<ProtoError>()?.kind() {
+                    ProtoErrorKind::Timeout => DnsError::Timeout,
+                    ProtoErrorKind::Io(_)
+                    | ProtoErrorKind::Busy
+                    | ProtoErrorKind::Canceled(_)
+                    | ProtoErrorKind::NoConnections => DnsError::Transport,
+                    _ => DnsError::Invalid,
+                })
+            })
+            .unwrap_or(DnsError::Internal);
+        variant(e)
+    }
+    pub fn response_code(&self) -> ResponseCode {
+        match self {
+            DnsError::Refused(_) => ResponseCode::Refused,
+            DnsError::Malformed(_) => ResponseCode::FormErr,
+            _ => ResponseCode::ServFail,
+        }
+    }
+}
+
+// Without the public suffix list, the last two labels stand in for the registrable domain
+pub fn minimized_name(name: &str) -> &str {
+    let labels = name.strip_suffix('.').unwrap_or(name);
+    match labels.rmatch_indices('.').nth(1) {
+        Some((i, _)) => &name[i + 1..],
+        None => name,
+    }
+}
+
+// Appended to the per-query log lines when LOG_CLIENT_IP is set
+pub fn client_suffix(client: IpAddr) -> String {
+    // Dual-stack sockets report IPv4 clients as IPv4-mapped IPv6 addresses
+    format!(" from {}", client.to_canonical())
+}
+
+pub struct HandlerOptions {
+    pub cache_max_entries: usize,
+    pub serve_stale: Duration,
+    pub cache_swr: Duration,
+    pub cache_prefetch: bool,
+    pub decision_cache_max: usize,
+    pub domain_stats_max: usize,
+    pub blocked_qtypes: Vec<RecordType>,
+    pub allowed_clients: Vec<IpNet>,
+    // Only narrows what BLOCK_QTYPES/BLOCK_AAAA leave, an ipv6 client still gets no AAAA there
+    pub client_families: Vec<(IpNet, AddressFamily)>,
+    pub rate_limit: Option<RateLimit>,
+    pub local_records: LocalRecords,
+    // Checked ahead of the local records, for names that are meant to exist upstream too
+    pub rewrites: LocalRecords,
+    pub local_ptr_authoritative: bool,
+    // RFC 6761 special-use names are answered here instead of upstream
+    pub rfc6761_local: bool,
+    pub rfc6761_nxdomain: Vec<Name>,
+    pub forward_ecs: Option<(u8, u8)>,
+    pub block_mode: BlockMode,
+    pub blocklist_mode: BlocklistMode,
+    pub block_cname_cloaking: bool,
+    pub block_explain: bool,
+    // Removes the ech SvcParam from HTTPS/SVCB answers, turning Encrypted Client Hello off
+    pub strip_ech: bool,
+    // DNS rebinding protection: public names lose answers pointing into private address space
+    pub rebind_protection: bool,
+    pub rebind_allow: Vec<Name>,
+    // Blocks every name outside the allowlist, blocklist entries can still deny names inside it
+    pub default_deny: bool,
+    pub strict_rd: bool,
+    // Classes other than IN are refused unless set, CHAOS identity queries are answered either way
+    pub allow_noninet_class: bool,
+    pub malformed_policy: MalformedPolicy,
+    pub edns_cookies: bool,
+    // Caps the payload size a UDP client may advertise, so large answers go over TCP instead of
+    // being fragmented. Stream transports carry any size and keep what the client asked for.
+    pub max_udp_payload: u16,
+    // Long names and deep label stacks are what tunnels encode their payload in
+    pub max_qname_len: usize,
+    pub max_labels: usize,
+    pub ttl_range: Option<(u32, u32)>,
+    pub type_ttl_ranges: FxHashMap<RecordType, (u32, u32)>,
+    pub sinkhole_ipv4: Ipv4Addr,
+    pub sinkhole_ipv6: Ipv6Addr,
+    pub block_ttl: u32,
+    pub upstream_strategy: UpstreamStrategy,
+    // QNAME minimization (RFC 9156) only means something when resolving iteratively. Every
+    // upstream here is a recursive resolver that needs the full name to answer, so the query sent
+    // upstream is a no-op to minimize and only what ndns itself records is cut down.
+    pub upstream_minimize: bool,
+    pub log_client_ip: bool,
+    // RFC 6052 prefix that AAAA answers are synthesized under for names with only A records
+    pub dns64_prefix: Option<Ipv6Net>,
+    // None answers the CHAOS identity queries with REFUSED
+    pub server_version: Option<String>,
+    pub upstream_race: usize,
+    pub upstream_max_inflight: usize,
+    pub upstream_timeout: Duration,
+    pub upstream_retries: u32,
+    pub upstream_retry_base: Duration,
+    pub response_send_timeout: Duration,
+}
+
+pub struct ClientPool {
+    clients: Vec<Mutex<Client>>,
+    next: AtomicUsize,
+}
+
+impl ClientPool {
+    pub fn new(client: Client, size: usize) -> Self {
+        Self {
+            clients: (0..size.max(1))
+                .map(|_| Mutex::new(client.clone()))
+                .collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+    async fn get(&self) -> Client {
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % self.clients.len();
+        self.clients[i].lock().await.clone()
+    }
+    async fn replace(&self, client: Client) {
+        for slot in &self.clients {
+            *slot.lock().await = client.clone();
+        }
+    }
+}
+
+pub struct Upstream {
+    pub addr: String,
+    pub client: ClientPool,
+    pub tcp_fallback: Option<Mutex<Client>>,
+    pub trust_anchors: Option<Arc<TrustAnchors>>,
+}
+
+impl Upstream {
+    async fn exchange<H: ClientHandle>(
+        mut handle: H,
+        name: Name,
+        query_class: DNSClass,
+        query_type: RecordType,
+        subnet: Option<ClientSubnet>,
+    ) -> Result<DnsResponse, ProtoError> {
+        let Some(subnet) = subnet else {
+            return handle.query(name, query_class, query_type).await;
+        };
+        let mut query = Query::query(name, query_type);
+        query.set_query_class(query_class);
+        let mut message = Message::query();
+        message.add_query(query).set_recursion_desired(true);
+        message
+            .extensions_mut()
+            .get_or_insert_with(Edns::new)
+            .set_max_payload(1232)
+            .set_version(0)
+            .options_mut()
+            .insert(EdnsOption::Subnet(subnet));
+        handle
+            .send(DnsRequest::new(message, DnsRequestOptions::default()))
+            .first_answer()
+            .await
+    }
+    async fn send(
+        &self,
+        client: Client,
+        name: Name,
+        query_class: DNSClass,
+        query_type: RecordType,
+        subnet: Option<ClientSubnet>,
+    ) -> anyhow::Result<DnsResponse> {
+        let Some(trust_anchors) = &self.trust_anchors else {
+            return Ok(Self::exchange(client, name, query_class, query_type, subnet).await?);
+        };
+        let response = Self::exchange(
+            DnssecDnsHandle::with_trust_anchor(client, trust_anchors.clone()),
+            name.clone(),
+            query_class,
+            query_type,
+            subnet,
+        )
+        .await?;
+        // Unsigned zones come back insecure and pass through, only a broken chain is rejected
+        if response
+            .answers()
+            .iter()
+            .chain(response.authorities())
+            .any(|record| record.proof().is_bogus())
+        {
+            anyhow::bail!("DNSSEC validation failed for {name}");
+        }
+        Ok(response)
+    }
+    async fn query(
+        &self,
+        name: Name,
+        query_class: DNSClass,
+        query_type: RecordType,
+        subnet: Option<ClientSubnet>,
+    ) -> anyhow::Result<DnsResponse> {
+        let client = self.client.get().await;
+        let response = self
+            .send(client, name.clone(), query_class, query_type, subnet)
+            .await?;
+        if let Some(tcp) = &self.tcp_fallback
+            && response.truncated()
+        {
+            log::debug!(
+                "Truncated response from {} for {name}, retrying over TCP",
+                self.addr
+            );
+            let client = { tcp.lock().await.clone() };
+            return self
+                .send(client, name, query_class, query_type, subnet)
+                .await;
+        }
+        Ok(response)
+    }
+}
+
+pub struct ForwardZone {
+    pub zone: Name,
+    pub upstreams: Vec<Upstream>,
+}
+
+pub struct DnsHandlerBuilder {
+    upstreams: Vec<Upstream>,
+    forward_zones: Vec<ForwardZone>,
+    blocklist: DomainList,
+    allowlist: DomainList,
+    options: HandlerOptions,
+    metrics: Arc<Metrics>,
+    query_log: Option<QueryLog>,
+}
+
+impl DnsHandlerBuilder {
+    pub fn upstreams(mut self, upstreams: Vec<Upstream>) -> Self {
+        self.upstreams = upstreams;
+        self
+    }
+    pub fn forward_zones(mut self, forward_zones: Vec<ForwardZone>) -> Self {
+        self.forward_zones = forward_zones;
+        self
+    }
+    pub fn blocklist(mut self, blocklist: DomainList) -> Self {
+        self.blocklist = blocklist;
+        self
+    }
+    pub fn allowlist(mut self, allowlist: DomainList) -> Self {
+        self.allowlist = allowlist;
+        self
+    }
+    pub fn query_log(mut self, query_log: Option<QueryLog>) -> Self {
+        self.query_log = query_log;
+        self
+    }
+    pub fn build(mut self) -> DnsHandler {
+        // Most specific zone first, so the first match is the longest suffix
+        self.forward_zones
+            .sort_by_key(|zone| std::cmp::Reverse(zone.zone.num_labels()));
+        let options = self.options;
+        DnsHandler {
+            upstreams: Arc::new(self.upstreams),
+            forward_zones: Arc::new(self.forward_zones),
+            next_upstream: Arc::new(AtomicUsize::new(0)),
+            upstream_slots: Arc::new(Semaphore::new(options.upstream_max_inflight)),
+            inflight: Arc::new(std::sync::Mutex::new(FxHashMap::default())),
+            cached_allow: Arc::new(DecisionCache::new(options.decision_cache_max)),
+            cached_block: Arc::new(BlockCache::new(options.decision_cache_max)),
+            blocklist: Arc::new(RwLock::new(self.blocklist)),
+            allowlist: Arc::new(RwLock::new(self.allowlist)),
+            cache: Arc::new(ResponseCache::new(
+                options.cache_max_entries,
+                options.serve_stale,
+                options.cache_swr,
+                options.cache_prefetch,
+            )),
+            domain_stats: Arc::new(DomainStats::new(options.domain_stats_max)),
+            rate_limiter: options
+                .rate_limit
+                .map(|limit| Arc::new(RateLimiter::new(limit))),
+            cookie_secret: options.edns_cookies.then(|| Arc::new(CookieSecret::new())),
+            options: Arc::new(options),
+            metrics: self.metrics,
+            query_log: self.query_log.map(Arc::new),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct DnsHandler {
-    upstream: Arc<Mutex<Client>>,
-    cached_allow: Arc<RwLock<FxHashSet<String>>>,
-    cached_block: Arc<RwLock<FxHashSet<String>>>,
-    blocklist: FxHashSet<String>,
+    upstreams: Arc<Vec<Upstream>>,
+    forward_zones: Arc<Vec<ForwardZone>>,
+    next_upstream: Arc<AtomicUsize>,
+    upstream_slots: Arc<Semaphore>,
+    inflight: Arc<std::sync::Mutex<FxHashMap<CacheKey, InflightQuery>>>,
+    cached_allow: Arc<DecisionCache>,
+    cached_block: Arc<BlockCache>,
+    blocklist: Arc<RwLock<DomainList>>,
+    allowlist: Arc<RwLock<DomainList>>,
+    cache: Arc<ResponseCache>,
+    options: Arc<HandlerOptions>,
+    metrics: Arc<Metrics>,
+    domain_stats: Arc<DomainStats>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    query_log: Option<Arc<QueryLog>>,
+    cookie_secret: Option<Arc<CookieSecret>>,
 }
 
 impl DnsHandler {
     const OLD_VERSION: u8 = 0;
-    pub fn new(upstream: Arc<Mutex<Client>>, blocklist: FxHashSet<String>) -> Self {
-        Self {
-            upstream,
-            cached_allow: Arc::new(RwLock::new(FxHashSet::default())),
-            cached_block: Arc::new(RwLock::new(FxHashSet::default())),
-            blocklist,
+    const SOA_MNAME: &str = "ndns.invalid.";
+    const SOA_RNAME: &str = "hostmaster.ndns.invalid.";
+    const DEFAULT_DENY_RULE: &str = "default deny";
+    // Everything beyond the options and metrics starts out empty
+    pub fn builder(options: HandlerOptions, metrics: Arc<Metrics>) -> DnsHandlerBuilder {
+        DnsHandlerBuilder {
+            upstreams: vec![],
+            forward_zones: vec![],
+            blocklist: DomainList::default(),
+            allowlist: DomainList::default(),
+            options,
+            metrics,
+            query_log: None,
         }
     }
-    fn does_end(name: &str, it: &str) -> bool {
-        if !name.ends_with(it) {
-            return false;
-        }
-        if name.chars().take(name.len() - it.len()).last() == Some('.') {
-            return true;
+    // Swaps a reconnected client in for the upstream at `addr`, zone being the forward zone it
+    // serves, if any
+    pub async fn replace_upstream_client(
+        &self,
+        zone: Option<&Name>,
+        addr: &str,
+        tcp_fallback: bool,
+        client: Client,
+    ) {
+        let upstreams = match zone {
+            Some(zone) => self
+                .forward_zones
+                .iter()
+                .find(|it| it.zone == *zone)
+                .map_or(&[][..], |it| it.upstreams.as_slice()),
+            None => self.upstreams.as_slice(),
+        };
+        for upstream in upstreams.iter().filter(|upstream| upstream.addr == addr) {
+            match (&upstream.tcp_fallback, tcp_fallback) {
+                (Some(fallback), true) => *fallback.lock().await = client.clone(),
+                (_, false) => upstream.client.replace(client.clone()).await,
+                (None, true) => {}
+            }
         }
-        false
     }
-    async fn is_blocked(&self, name: &str) -> bool {
-        if self.cached_block.read().await.contains(name) {
-            return true;
+    pub fn domain_stats(&self) -> Arc<DomainStats> {
+        self.domain_stats.clone()
+    }
+    pub async fn list_sizes(&self) -> (usize, usize) {
+        (
+            self.blocklist.read().await.len(),
+            self.allowlist.read().await.len(),
+        )
+    }
+    pub async fn response_cache_size(&self) -> usize {
+        self.cache.len().await
+    }
+    pub fn decision_cache_sizes(&self) -> (usize, usize) {
+        (self.cached_allow.len(), self.cached_block.len())
+    }
+    pub async fn set_lists(&self, blocklist: DomainList, allowlist: DomainList) {
+        let mut current = self.blocklist.write().await;
+        *current = blocklist;
+        *self.allowlist.write().await = allowlist;
+        self.cached_allow.clear();
+        self.cached_block.clear();
+    }
+    // The blocklist entry that matched, if the name is blocked
+    #[tracing::instrument(skip_all, fields(qname = name))]
+    async fn is_blocked(&self, name: &str, query_type: RecordType) -> Option<String> {
+        // Hold the blocklist for the whole evaluation so a concurrent reload can't clear the
+        // caches between our match and our cache insert.
+        let blocklist = self.blocklist.read().await;
+        let allowlist = self.allowlist.read().await;
+
+        // DNS names are case-insensitive. Decisions only depend on the type when an entry is
+        // scoped to it, so every other type keeps sharing one cached decision per name.
+        let name = &name.to_ascii_lowercase();
+        let key = &if blocklist.has_typed(query_type) || allowlist.has_typed(query_type) {
+            format!("{name} {query_type}")
+        } else {
+            name.clone()
+        };
+        if let Some(rule) = self.cached_block.get(key) {
+            return Some(rule);
         }
 
-        if self.cached_allow.read().await.contains(name) {
-            return false;
+        if self.cached_allow.contains(key) {
+            return None;
         }
 
-        for it in &self.blocklist {
-            if Self::does_end(name, it) {
-                if self.cached_block.write().await.insert(name.to_string()) {
-                    log::info!("Add {} to cached blocklist", name);
+        match Self::decide(&blocklist, &allowlist, name, Some(query_type)) {
+            Decision::Blocked(rule) => {
+                if self.cached_block.insert(key, rule.clone()) {
+                    log::info!("Add {} to cached blocklist", key);
                 }
-                return true;
+                Some(rule)
+            }
+            Decision::Allowed => {
+                if self.cached_allow.insert(key, ()) {
+                    log::info!("Add {} to cached allowlist", key);
+                }
+                None
+            }
+            Decision::Unlisted if self.options.default_deny => {
+                let rule = Self::DEFAULT_DENY_RULE.to_string();
+                self.cached_block.insert(key, rule.clone());
+                Some(rule)
+            }
+            Decision::Unlisted => {
+                self.cached_allow.insert(key, ());
+                None
             }
         }
-
-        self.cached_allow.write().await.insert(name.to_string());
-        false
     }
+    // The more specific rule wins, so an allow entry can carve a name out of a blocked parent
+    // and a block entry can do the same inside an allowed one. A tie goes to the allowlist.
+    // Without a query type only the entries that apply to every type count.
+    fn decide(
+        blocklist: &DomainList,
+        allowlist: &DomainList,
+        name: &str,
+        query_type: Option<RecordType>,
+    ) -> Decision {
+        let (allowed, blocked) = match query_type {
+            Some(query_type) => (
+                allowlist.most_specific_for(name, query_type),
+                blocklist.most_specific_for(name, query_type),
+            ),
+            None => (allowlist.most_specific(name), blocklist.most_specific(name)),
+        };
+        match (allowed, blocked) {
+            (allowed, Some((specificity, rule)))
+                if allowed.is_none_or(|(allowed, _)| specificity > allowed) =>
+            {
+                Decision::Blocked(rule.to_string())
+            }
+            (Some(_), _) => Decision::Allowed,
+            (None, _) => Decision::Unlisted,
+        }
+    }
+    pub fn decision_snapshot(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(postcard::to_stdvec(&self.cached_block.entries())?)
+    }
+    // Decisions are only as good as the lists they were made with, so each one is made again and
+    // kept only if the current lists still block the name with the same rule
+    pub async fn restore_decisions(&self, bytes: &[u8]) -> anyhow::Result<(usize, usize)> {
+        let decisions = postcard::from_bytes::<Vec<(String, String)>>(bytes)?;
+        let blocklist = self.blocklist.read().await;
+        let allowlist = self.allowlist.read().await;
+        let mut restored = 0;
+        for (key, rule) in &decisions {
+            // Type-scoped decisions are cached as `name TYPE`
+            let (name, query_type) = match key.split_once(' ') {
+                Some((name, query_type)) => {
+                    match query_type.to_ascii_uppercase().parse::<RecordType>() {
+                        Ok(query_type) => (name, Some(query_type)),
+                        Err(_) => continue,
+                    }
+                }
+                None => (key.as_str(), None),
+            };
+            let still_blocked = match Self::decide(&blocklist, &allowlist, name, query_type) {
+                Decision::Blocked(current) => current == *rule,
+                Decision::Unlisted => self.options.default_deny && rule == Self::DEFAULT_DENY_RULE,
+                Decision::Allowed => false,
+            };
+            if still_blocked && self.cached_block.insert(key, rule.clone()) {
+                restored += 1;
+            }
+        }
+        Ok((restored, decisions.len() - restored))
+    }
+    pub async fn save_decision_cache(&self, path: &str) -> anyhow::Result<()> {
+        tokio::fs::write(path, self.decision_snapshot()?).await?;
+        log::info!("Saved block decisions to {path}");
+        Ok(())
+    }
+    pub async fn load_decision_cache(&self, path: &str) {
+        let bytes = match tokio::fs::read(path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+            Err(e) => {
+                log::warn!("Failed to read decision cache {path}: {e}");
+                return;
+            }
+        };
+        match self.restore_decisions(&bytes).await {
+            Ok((restored, stale)) => log::info!(
+                "Restored {restored} block decisions from {path}, dropped {stale} stale ones"
+            ),
+            Err(e) => log::warn!("Failed to restore decision cache {path}: {e:#}"),
+        }
+    }
+    #[tracing::instrument(skip_all, fields(qname = %name, qtype = %query_type))]
     async fn forward_to_upstream(
         &self,
         name: Name,
         query_class: DNSClass,
         query_type: RecordType,
+        subnet: Option<ClientSubnet>,
     ) -> anyhow::Result<DnsResponse> {
-        let mut upstream = { self.upstream.lock().await.clone() };
-        let response = upstream.query(name, query_class, query_type).await?;
-        Ok(response)
+        let upstreams = self
+            .forward_zones
+            .iter()
+            .find(|zone| zone.zone.zone_of(&name))
+            .map_or(self.upstreams.as_slice(), |zone| zone.upstreams.as_slice());
+        let first = match self.options.upstream_strategy {
+            UpstreamStrategy::Failover | UpstreamStrategy::Race => 0,
+            // fetch_add wraps on overflow, and the modulo keeps the index in range either way
+            UpstreamStrategy::RoundRobin => self.next_upstream.fetch_add(1, Ordering::Relaxed),
+        };
+        // Queue for a slot no longer than a single query may take, then give up with ServFail
+        let _slot = match self.upstream_slots.try_acquire() {
+            Ok(slot) => slot,
+            Err(_) => {
+                log::debug!(
+                    "All {} upstream query slots are busy, queueing {name}",
+                    self.options.upstream_max_inflight
+                );
+                tokio::time::timeout(self.options.upstream_timeout, self.upstream_slots.acquire())
+                    .await
+                    .ok()
+                    .and_then(Result::ok)
+                    .ok_or_else(|| {
+                        log::warn!("Upstream query limit saturated, dropping {name}");
+                        UpstreamError::Saturated(self.options.upstream_max_inflight)
+                    })?
+            }
+        };
+        let _timer = self.metrics.upstream_latency.start_timer();
+        let mut last_error = None;
+        for attempt in 0..=self.options.upstream_retries {
+            if attempt > 0 {
+                // Every wait is capped at the query timeout, so the worst case stays a fixed
+                // multiple of UPSTREAM_TIMEOUT_MS however many retries are configured
+                let delay = self
+                    .options
+                    .upstream_retry_base
+                    .saturating_mul(2u32.saturating_pow(attempt - 1))
+                    .min(self.options.upstream_timeout);
+                tokio::time::sleep(delay).await;
+                log::debug!("Retrying {name} upstream, attempt {}", attempt + 1);
+            }
+            let mut transient = false;
+            if self.options.upstream_strategy == UpstreamStrategy::Race {
+                // Nothing is spawned, so returning drops and cancels the queries still in flight
+                let name = &name;
+                let mut racing = upstreams
+                    .iter()
+                    .take(self.options.upstream_race)
+                    .map(|upstream| async move {
+                        let result = self
+                            .query_upstream(upstream, name, query_class, query_type, subnet)
+                            .await;
+                        (upstream, result)
+                    })
+                    .collect::<FuturesUnordered<_>>();
+                while let Some((upstream, result)) = racing.next().await {
+                    match result {
+                        Ok(response) => {
+                            log::debug!("Upstream {} won the race for {name}", upstream.addr);
+                            return Ok(response);
+                        }
+                        Err((e, retry)) => {
+                            transient |= retry;
+                            last_error = Some(e);
+                        }
+                    }
+                }
+            } else {
+                for i in 0..upstreams.len() {
+                    let upstream = &upstreams[(first + i) % upstreams.len()];
+                    match self
+                        .query_upstream(upstream, &name, query_class, query_type, subnet)
+                        .await
+                    {
+                        Ok(response) => {
+                            log::trace!("Upstream {} answered {name}", upstream.addr);
+                            return Ok(response);
+                        }
+                        Err((e, retry)) => {
+                            transient |= retry;
+                            last_error = Some(e);
+                        }
+                    }
+                }
+            }
+            // An upstream that answered at all, even with ServFail, was reachable and isn't retried
+            if !transient {
+                break;
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("No upstream configured")))
+    }
+    // The error comes with whether it is worth retrying
+    async fn query_upstream(
+        &self,
+        upstream: &Upstream,
+        name: &Name,
+        query_class: DNSClass,
+        query_type: RecordType,
+        subnet: Option<ClientSubnet>,
+    ) -> Result<DnsResponse, (anyhow::Error, bool)> {
+        // Each upstream gets its own deadline so failover still has time to try the next one
+        match tokio::time::timeout(
+            self.options.upstream_timeout,
+            upstream.query(name.clone(), query_class, query_type, subnet),
+        )
+        .await
+        {
+            // Another upstream may well be willing to answer
+            Ok(Ok(response)) if response.response_code() == ResponseCode::Refused => {
+                log::debug!("Upstream {} refused {name}", upstream.addr);
+                self.metrics.upstream_errors.inc();
+                Err((UpstreamError::Refused(upstream.addr.clone()).into(), false))
+            }
+            Ok(Ok(response)) => Ok(response),
+            Err(_) => {
+                log::warn!(
+                    "Upstream {} timed out after {}ms for {name}",
+                    upstream.addr,
+                    self.options.upstream_timeout.as_millis()
+                );
+                self.metrics.upstream_timeouts.inc();
+                Err((UpstreamError::Timeout(upstream.addr.clone()).into(), true))
+            }
+            Ok(Err(e)) => {
+                log::debug!("Upstream {} failed for {name}: {e}", upstream.addr);
+                self.metrics.upstream_errors.inc();
+                let transient = Self::is_transient(&e);
+                Err((e, transient))
+            }
+        }
+    }
+    fn is_transient(e: &anyhow::Error) -> bool {
+        e.downcast_ref::<ProtoError>().is_some_and(|e| {
+            matches!(
+                e.kind(),
+                ProtoErrorKind::Io(_) | ProtoErrorKind::Timeout | ProtoErrorKind::Busy
+            )
+        })
+    }
+    // An upstream that answers SERVFAIL is reachable but can't resolve, which isn't healthy either
+    pub async fn probe(&self, name: Name) -> anyhow::Result<()> {
+        let response = self
+            .forward_to_upstream(name, DNSClass::IN, RecordType::A, None)
+            .await?;
+        if response.response_code() == ResponseCode::ServFail {
+            anyhow::bail!("upstream answered SERVFAIL");
+        }
+        Ok(())
+    }
+    async fn resolve(
+        &self,
+        name: Name,
+        query_class: DNSClass,
+        query_type: RecordType,
+        subnet: Option<ClientSubnet>,
+    ) -> anyhow::Result<(Answer, Option<Duration>)> {
+        let key = (name, query_class, query_type);
+        // Answers tailored to a client subnet must not be shared through the cache
+        let cacheable = subnet.is_none();
+        if cacheable && let Some((answer, prefetch)) = self.cache.get(&key).await {
+            log::trace!("Cache hit for {}", key.0);
+            self.metrics.cache_hits.inc();
+            if prefetch {
+                let handler = self.clone();
+                tokio::spawn(async move { handler.prefetch(key).await });
+            }
+            return Ok((answer, None));
+        }
+        self.metrics.cache_misses.inc();
+        let started = Instant::now();
+        let answer = if cacheable {
+            self.coalesced_query(&key).await
+        } else {
+            self.forward_to_upstream(key.0.clone(), query_class, query_type, subnet)
+                .await
+                .map(|response| self.upstream_answer(&key.0, response))
+        };
+        let elapsed = started.elapsed();
+        // NXDOMAIN and other real answers go through as is, only an unreachable or failing upstream falls back
+        let failed = match &answer {
+            Ok(answer) => answer.response_code == ResponseCode::ServFail,
+            Err(_) => true,
+        };
+        if cacheable
+            && failed
+            && let Some(stale) = self.cache.get_stale(&key).await
+        {
+            log::info!("Upstream failed for {}, serving stale answer", key.0);
+            return Ok((stale, Some(elapsed)));
+        }
+        Ok((answer?, Some(elapsed)))
+    }
+    // A miss for a key that is already being asked upstream waits for that query instead of
+    // sending its own. The query caches its answer and clears its own entry when it completes.
+    async fn coalesced_query(&self, key: &CacheKey) -> anyhow::Result<Answer> {
+        let query = self
+            .inflight
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_insert_with(|| {
+                let handler = self.clone();
+                let key = key.clone();
+                async move {
+                    let answer = handler
+                        .forward_to_upstream(key.0.clone(), key.1, key.2, None)
+                        .await
+                        .map(|response| handler.upstream_answer(&key.0, response));
+                    if let Ok(answer) = &answer {
+                        handler.cache.insert(key.clone(), answer.clone()).await;
+                    }
+                    handler.inflight.lock().unwrap().remove(&key);
+                    answer.map_err(Arc::new)
+                }
+                .boxed()
+                .shared()
+            })
+            .clone();
+        query.await.map_err(|e| SharedError(e).into())
+    }
+    // Clamped before caching, so MIN_TTL/MAX_TTL also decide how long the cache keeps the answer
+    fn upstream_answer(&self, name: &Name, response: DnsResponse) -> Answer {
+        let mut answer = Answer::from(response);
+        if self.options.strip_ech {
+            answer = answer.without_ech();
+        }
+        if self.options.rebind_protection {
+            self.scrub_private_addresses(name, &mut answer);
+        }
+        if self.options.ttl_range.is_none() && self.options.type_ttl_ranges.is_empty() {
+            return answer;
+        }
+        answer.with_clamped_ttl(self.options.ttl_range, &self.options.type_ttl_ranges)
+    }
+    // Names in a forwarded zone are the local network's own and may point anywhere in it
+    fn scrub_private_addresses(&self, name: &Name, answer: &mut Answer) {
+        if self
+            .forward_zones
+            .iter()
+            .any(|zone| zone.zone.zone_of(name))
+            || self
+                .options
+                .rebind_allow
+                .iter()
+                .any(|allowed| allowed.zone_of(name))
+        {
+            return;
+        }
+        let before = answer.answers.len();
+        answer.answers.retain(|record| match record.data() {
+            RData::A(A(ip)) => !LocalRecords::is_private(IpAddr::V4(*ip)),
+            // IPv4-mapped addresses are checked as the IPv4 address they carry
+            RData::AAAA(AAAA(ip)) => !LocalRecords::is_private(IpAddr::V6(*ip).to_canonical()),
+            _ => true,
+        });
+        let removed = before - answer.answers.len();
+        if removed > 0 {
+            log::debug!("Removed {removed} private addresses from the answer for {name}");
+            answer.authentic_data = false;
+        }
+    }
+    pub async fn save_cache_snapshot(&self, path: &str) -> anyhow::Result<()> {
+        tokio::fs::write(path, self.cache.snapshot().await?).await?;
+        log::info!("Saved response cache to {path}");
+        Ok(())
+    }
+    // A missing snapshot is the normal first start, anything else unreadable is only worth a warning
+    pub async fn load_cache_snapshot(&self, path: &str) {
+        let bytes = match tokio::fs::read(path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+            Err(e) => {
+                log::warn!("Failed to read cache snapshot {path}: {e}");
+                return;
+            }
+        };
+        match self.cache.restore(&bytes).await {
+            Ok(restored) => log::info!("Restored {restored} cache entries from {path}"),
+            Err(e) => log::warn!("Failed to restore cache snapshot {path}: {e:#}"),
+        }
+    }
+    // Resolves the given keys into the cache before serving, a failed entry only costs its own answer
+    pub async fn warm_up(&self, keys: Vec<CacheKey>) {
+        const CONCURRENCY: usize = 16;
+        let total = keys.len();
+        let slots = Arc::new(Semaphore::new(CONCURRENCY));
+        let mut queries = keys
+            .into_iter()
+            .map(|key| {
+                let slots = slots.clone();
+                async move {
+                    let _slot = slots.acquire().await.ok()?;
+                    match self
+                        .forward_to_upstream(key.0.clone(), key.1, key.2, None)
+                        .await
+                    {
+                        Ok(response) => {
+                            let answer = self.upstream_answer(&key.0, response);
+                            self.cache.insert(key, answer).await;
+                            Some(())
+                        }
+                        Err(e) => {
+                            log::warn!("Failed to warm up {} {}: {e:#}", key.0, key.2);
+                            None
+                        }
+                    }
+                }
+            })
+            .collect::<FuturesUnordered<_>>();
+        let mut primed = 0;
+        while let Some(result) = queries.next().await {
+            primed += result.is_some() as usize;
+        }
+        log::info!("Primed {primed} of {total} cache warmup entries");
+    }
+    async fn prefetch(&self, key: CacheKey) {
+        log::trace!("Prefetching {}", key.0);
+        match self
+            .forward_to_upstream(key.0.clone(), key.1, key.2, None)
+            .await
+        {
+            Ok(response) => {
+                let answer = self.upstream_answer(&key.0, response);
+                self.cache.insert(key, answer).await
+            }
+            Err(e) => log::debug!("Prefetch of {} failed: {e}", key.0),
+        }
+    }
+    // The longest matching prefix decides, clients outside every range get both families
+    fn client_family(&self, client: IpAddr) -> AddressFamily {
+        let client = client.to_canonical();
+        self.options
+            .client_families
+            .iter()
+            .filter(|(net, _)| net.contains(&client))
+            .max_by_key(|(net, _)| net.prefix_len())
+            .map_or(AddressFamily::Both, |(_, family)| *family)
+    }
+    fn client_subnet(&self, request: &Request) -> Option<ClientSubnet> {
+        let (prefix_v4, prefix_v6) = self.options.forward_ecs?;
+        let Some(EdnsOption::Subnet(subnet)) = request.edns()?.options().get(EdnsCode::Subnet)
+        else {
+            return None;
+        };
+        let max_prefix = match subnet.addr() {
+            IpAddr::V4(_) => prefix_v4,
+            IpAddr::V6(_) => prefix_v6,
+        };
+        // Truncate the address too, not just the prefix length, so no host bits leak upstream
+        let net = IpNet::new(subnet.addr(), subnet.source_prefix().min(max_prefix)).ok()?;
+        Some(ClientSubnet::from(net.trunc()))
+    }
+    fn log_query(
+        &self,
+        request: &Request,
+        name: &str,
+        query_type: RecordType,
+        blocked: bool,
+        response_code: ResponseCode,
+        upstream_latency: Option<Duration>,
+    ) {
+        let Some(query_log) = &self.query_log else {
+            return;
+        };
+        let name = if self.options.upstream_minimize {
+            minimized_name(name)
+        } else {
+            name
+        };
+        query_log.record(QueryLogEntry {
+            timestamp_ms: QueryLogEntry::now_ms(),
+            client: request.src().ip(),
+            name: name.to_string(),
+            qtype: query_type.to_string(),
+            blocked,
+            rcode: format!("{response_code:?}"),
+            upstream_ms: upstream_latency.map(|latency| latency.as_secs_f64() * 1000.0),
+        });
     }
 
     async fn handle_query<R: ResponseHandler>(
@@ -74,55 +1124,561 @@ impl DnsHandler {
         response_edns: Option<Edns>,
         request: &Request,
         response_handle: R,
-    ) -> anyhow::Result<ResponseInfo> {
-        let request_info = request.request_info()?;
+    ) -> Result<ResponseInfo, DnsError> {
+        let request_info = request.request_info().map_err(DnsError::Malformed)?;
 
-        let name = request_info.query.name().into_name()?;
+        let name = request_info
+            .query
+            .name()
+            .into_name()
+            .map_err(DnsError::Malformed)?;
         let class = request_info.query.query_class();
         let qtype = request_info.query.query_type();
         let name_utf8 = name.to_utf8();
+        self.metrics.queries.inc();
+        let span = tracing::Span::current();
+        span.record("qname", name_utf8.as_str());
+        span.record("qtype", tracing::field::display(qtype));
+
+        // Every way out of answer_query is logged here, so no early return can skip or garble it
+        match self
+            .answer_query(response_edns, request, &name, class, qtype, response_handle)
+            .await
+        {
+            Ok((info, blocked, upstream_latency)) => {
+                span.record("blocked", blocked);
+                self.log_query(
+                    request,
+                    &name_utf8,
+                    qtype,
+                    blocked,
+                    info.response_code(),
+                    upstream_latency,
+                );
+                Ok(info)
+            }
+            Err(e) => {
+                self.log_query(request, &name_utf8, qtype, false, e.response_code(), None);
+                Err(e)
+            }
+        }
+    }
 
-        let upstream_response = if self.is_blocked(&name_utf8).await {
-            log::trace!("Blocked {name_utf8}");
-            None
+    // The response sent, whether the name was blocked and how long upstream took, if it was asked
+    async fn answer_query<R: ResponseHandler>(
+        &self,
+        response_edns: Option<Edns>,
+        request: &Request,
+        name: &Name,
+        class: DNSClass,
+        qtype: RecordType,
+        response_handle: R,
+    ) -> Result<(ResponseInfo, bool, Option<Duration>), DnsError> {
+        let name_utf8 = name.to_utf8();
+        let asker = if self.options.log_client_ip {
+            client_suffix(request.src().ip())
         } else {
-            log::trace!("Resolving {name_utf8}");
-            Some(self.forward_to_upstream(name.clone(), class, qtype).await?)
+            String::new()
         };
 
-        let response_builder = MessageResponseBuilder::from_message_request(request);
-
-        match upstream_response {
-            Some(response) => {
-                let mut response_header = Header::response_from_request(request.header());
-                response_header.set_recursion_available(response.recursion_available());
-                response_header.set_response_code(response.response_code());
+        // CHAOS names only ever describe this server, so they are never forwarded
+        if class == DNSClass::CH {
+            let info = self
+                .send_chaos(response_edns, request, name, qtype, response_handle)
+                .await?;
+            return Ok((info, false, None));
+        }
 
-                Self::send_response(
+        if class != DNSClass::IN && !self.options.allow_noninet_class {
+            log::trace!("Refusing {class} query for {name_utf8}");
+            let info = self
+                .send_response(
                     response_edns,
-                    response_builder.build(
-                        response_header,
-                        response.answers(),
-                        response.authorities(),
-                        &[],
-                        response.additionals(),
-                    ),
+                    MessageResponseBuilder::from_message_request(request)
+                        .error_msg(request.header(), ResponseCode::Refused),
                     response_handle,
                 )
-                .await
+                .await?;
+            return Ok((info, false, None));
+        }
+
+        let local = self
+            .options
+            .rewrites
+            .lookup(name, qtype)
+            .or_else(|| self.options.local_records.lookup(name, qtype))
+            .or_else(|| {
+                self.options
+                    .rfc6761_local
+                    .then(|| LocalRecords::special_use(name, qtype))
+                    .flatten()
+            });
+        let (target, chain) = match local {
+            Some(LocalAnswer::Records(records)) => {
+                log::trace!("Answering {name_utf8} from local records");
+                let response_header = Self::synthesized_header(request);
+                let soa = if records.is_empty() {
+                    self.negative_soa(name)
+                } else {
+                    vec![]
+                };
+                let info = self
+                    .send_response(
+                        response_edns,
+                        MessageResponseBuilder::from_message_request(request).build(
+                            response_header,
+                            &records,
+                            &soa,
+                            &[],
+                            &[],
+                        ),
+                        response_handle,
+                    )
+                    .await?;
+                return Ok((info, false, None));
+            }
+            // The chain is only local, where it ends is checked like any other query
+            Some(LocalAnswer::Forward(chain, target)) => {
+                log::trace!("Following local CNAME {name_utf8} to {target}");
+                (target, chain)
             }
-            None => {
-                Self::send_response(
+            None if (self.options.local_ptr_authoritative
+                && LocalRecords::is_private_reverse(name))
+                || (self.options.rfc6761_local
+                    && self
+                        .options
+                        .rfc6761_nxdomain
+                        .iter()
+                        .any(|zone| zone.zone_of(name))) =>
+            {
+                log::trace!("Answering {name_utf8} locally as nonexistent");
+                let mut response_header = Self::synthesized_header(request);
+                response_header.set_response_code(ResponseCode::NXDomain);
+                let info = self
+                    .send_response(
+                        response_edns,
+                        MessageResponseBuilder::from_message_request(request).build(
+                            response_header,
+                            &[],
+                            &self.negative_soa(name),
+                            &[],
+                            &[],
+                        ),
+                        response_handle,
+                    )
+                    .await?;
+                return Ok((info, false, None));
+            }
+            None => (name.clone(), vec![]),
+        };
+
+        // Blocklist entries are stored as punycode, so match the ASCII form of the query
+        let mut rule = self.is_blocked(&target.to_ascii(), qtype).await;
+        if let Some(matched) = &rule
+            && self.options.blocklist_mode == BlocklistMode::Monitor
+        {
+            log::info!("Would block {name_utf8} (matched {matched})");
+            rule = None;
+        }
+        let blocked = rule.is_some();
+        self.domain_stats.update(&name_utf8, |count| {
+            count.total += 1;
+            count.blocked += blocked as u64;
+        });
+
+        // Blocked names never reach `resolve`, so synthesized NXDOMAINs stay out of the response cache
+        // and names inside a forwarded zone are still subject to the blocklist.
+        if let Some(rule) = rule {
+            log::debug!("Blocked {name_utf8}{asker} (matched {rule})");
+            self.metrics.blocked.inc();
+            let info = self
+                .send_blocked(response_edns, request, name, qtype, &rule, response_handle)
+                .await?;
+            return Ok((info, true, None));
+        }
+
+        let family = self.client_family(request.src().ip());
+        if self.options.blocked_qtypes.contains(&qtype) || !family.allows(qtype) {
+            log::trace!("Suppressed {qtype} query for {name_utf8}");
+            let info = self
+                .send_suppressed(response_edns, request, name, qtype, response_handle)
+                .await?;
+            return Ok((info, false, None));
+        }
+
+        // RFC 1034 4.3.1: without RD only what is already known may be answered
+        if self.options.strict_rd
+            && !request.header().recursion_desired()
+            && !self.cache.contains(&(target.clone(), class, qtype)).await
+        {
+            log::trace!("Refusing non-recursive query for {name_utf8}");
+            let info = self
+                .send_response(
                     response_edns,
-                    response_builder.error_msg(request.header(), ResponseCode::NXDomain),
+                    MessageResponseBuilder::from_message_request(request)
+                        .error_msg(request.header(), ResponseCode::Refused),
                     response_handle,
                 )
+                .await?;
+            return Ok((info, false, None));
+        }
+
+        log::trace!("Resolving {name_utf8}{asker}");
+        let (mut answer, upstream_latency) = match self
+            .resolve(target.clone(), class, qtype, self.client_subnet(request))
+            .await
+        {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                let info = self
+                    .send_upstream_failure(response_edns, request, &name_utf8, e, response_handle)
+                    .await?;
+                return Ok((info, false, None));
+            }
+        };
+
+        if qtype == RecordType::AAAA
+            && let Some(prefix) = self.options.dns64_prefix
+            // RFC 6147 5.5: a validating client asked for the records exactly as signed
+            && !request.header().checking_disabled()
+            && let Some(synthesized) = self
+                .synthesize_dns64(prefix, &target, class, &answer, self.client_subnet(request))
                 .await
+        {
+            log::trace!("Synthesized AAAA for {name_utf8} from its A records");
+            answer = synthesized;
+        }
+
+        if self.options.block_cname_cloaking
+            && let Some((target, rule)) = self.cloaked_target(&answer, qtype).await
+        {
+            if self.options.blocklist_mode == BlocklistMode::Monitor {
+                log::info!("Would block {name_utf8} (CNAME target {target} matched {rule})");
+            } else {
+                log::info!("Blocked {name_utf8}{asker} via CNAME target {target} (matched {rule})");
+                self.metrics.blocked.inc();
+                self.domain_stats
+                    .update(&name_utf8, |count| count.blocked += 1);
+                let info = self
+                    .send_blocked(response_edns, request, name, qtype, &rule, response_handle)
+                    .await?;
+                return Ok((info, true, upstream_latency));
             }
         }
+
+        let span = tracing::Span::current();
+        span.record("cache_hit", upstream_latency.is_none());
+        if let Some(latency) = upstream_latency {
+            span.record("upstream_ms", latency.as_secs_f64() * 1000.0);
+        }
+
+        // ANY and CNAME-chased answers can still carry the family the client isn't meant to see
+        if family != AddressFamily::Both {
+            answer
+                .answers
+                .retain(|record| family.allows(record.record_type()));
+        }
+
+        let mut response_header = Header::response_from_request(request.header());
+        response_header.set_recursion_available(answer.recursion_available);
+        // Nothing signed the local CNAMEs in front of the upstream answer
+        response_header.set_authentic_data(answer.authentic_data && chain.is_empty());
+        response_header.set_response_code(answer.response_code);
+        let answers = chain.into_iter().chain(answer.answers).collect::<Vec<_>>();
+
+        let info = self
+            .send_response(
+                response_edns,
+                MessageResponseBuilder::from_message_request(request).build(
+                    response_header,
+                    &answers,
+                    &answer.authorities,
+                    &[],
+                    &answer.additionals,
+                ),
+                response_handle,
+            )
+            .await?;
+        Ok((info, false, upstream_latency))
     }
 
+    // RFC 6147: only an empty AAAA answer is synthesized over, never NXDOMAIN or real AAAA records
+    async fn synthesize_dns64(
+        &self,
+        prefix: Ipv6Net,
+        name: &Name,
+        class: DNSClass,
+        answer: &Answer,
+        subnet: Option<ClientSubnet>,
+    ) -> Option<Answer> {
+        if answer.response_code != ResponseCode::NoError
+            || answer
+                .answers
+                .iter()
+                .any(|record| record.record_type() == RecordType::AAAA)
+        {
+            return None;
+        }
+        let (a_answer, _) = self
+            .resolve(name.clone(), class, RecordType::A, subnet)
+            .await
+            .ok()?;
+        // RFC 6147 5.1.7: no longer than the A records, nor than the negative AAAA answer
+        let max_ttl = answer.negative_ttl().unwrap_or(u32::MAX);
+        let mut synthesized = false;
+        let answers = a_answer
+            .answers
+            .iter()
+            .filter(|record| record.record_type() != RecordType::RRSIG)
+            .map(|record| match record.data() {
+                RData::A(A(ip)) => {
+                    synthesized = true;
+                    Record::from_rdata(
+                        record.name().clone(),
+                        record.ttl().min(max_ttl),
+                        RData::AAAA(AAAA(Self::embed_ipv4(prefix, *ip))),
+                    )
+                }
+                _ => record.clone(),
+            })
+            .collect();
+        synthesized.then(|| Answer {
+            response_code: ResponseCode::NoError,
+            recursion_available: a_answer.recursion_available,
+            // Synthesized records can't validate, so they are never reported as authentic
+            authentic_data: false,
+            answers,
+            authorities: vec![],
+            additionals: vec![],
+        })
+    }
+    // RFC 6052 2.2: the address follows the prefix, skipping over the reserved bits 64 to 71
+    fn embed_ipv4(prefix: Ipv6Net, ip: Ipv4Addr) -> Ipv6Addr {
+        let mut octets = prefix.network().octets();
+        let mut position = prefix.prefix_len() as usize / 8;
+        octets[position..].fill(0);
+        for octet in ip.octets() {
+            if position == 8 {
+                position += 1;
+            }
+            octets[position] = octet;
+            position += 1;
+        }
+        Ipv6Addr::from(octets)
+    }
+
+    // Trackers hide behind first-party names that CNAME to a blocked domain
+    async fn cloaked_target(
+        &self,
+        answer: &Answer,
+        query_type: RecordType,
+    ) -> Option<(Name, String)> {
+        for record in &answer.answers {
+            if let RData::CNAME(CNAME(target)) = record.data()
+                && let Some(rule) = self.is_blocked(&target.to_ascii(), query_type).await
+            {
+                return Some((target.clone(), rule));
+            }
+        }
+        None
+    }
+
+    fn sinkhole_records(&self, name: &Name, query_type: RecordType) -> Vec<Record> {
+        let rdata = match query_type {
+            RecordType::A => RData::A(A(self.options.sinkhole_ipv4)),
+            RecordType::AAAA => RData::AAAA(AAAA(self.options.sinkhole_ipv6)),
+            _ => return vec![],
+        };
+        vec![Record::from_rdata(
+            name.clone(),
+            self.options.block_ttl,
+            rdata,
+        )]
+    }
+
+    // Answers made up by ndns itself are authoritative, and recursion is offered for everything else.
+    // RD is echoed from the request by response_from_request.
+    fn synthesized_header(request: &Request) -> Header {
+        let mut header = Header::response_from_request(request.header());
+        header.set_authoritative(true);
+        header.set_recursion_available(true);
+        header
+    }
+
+    // RFC 2308: a negative answer needs an SOA in the authority section to be cacheable. There is
+    // no real zone behind a synthesized answer, so the queried name stands in as the owner.
+    fn negative_soa(&self, name: &Name) -> Vec<Record> {
+        let soa = SOA::new(
+            Name::from_ascii(Self::SOA_MNAME).unwrap(),
+            Name::from_ascii(Self::SOA_RNAME).unwrap(),
+            1,
+            1800,
+            900,
+            604800,
+            self.options.block_ttl,
+        );
+        vec![Record::from_rdata(
+            name.clone(),
+            self.options.block_ttl,
+            RData::SOA(soa),
+        )]
+    }
+
+    async fn send_suppressed<R: ResponseHandler>(
+        &self,
+        response_edns: Option<Edns>,
+        request: &Request,
+        name: &Name,
+        query_type: RecordType,
+        response_handle: R,
+    ) -> Result<ResponseInfo, DnsError> {
+        let response_builder = MessageResponseBuilder::from_message_request(request);
+        // ANY is refused outright as an amplification vector, other listed types get NODATA
+        if query_type == RecordType::ANY {
+            return self
+                .send_response(
+                    response_edns,
+                    response_builder.error_msg(request.header(), ResponseCode::NotImp),
+                    response_handle,
+                )
+                .await;
+        }
+        let soa = self.negative_soa(name);
+        self.send_response(
+            response_edns,
+            response_builder.build(Self::synthesized_header(request), &[], &soa, &[], &[]),
+            response_handle,
+        )
+        .await
+    }
+
+    async fn send_chaos<R: ResponseHandler>(
+        &self,
+        response_edns: Option<Edns>,
+        request: &Request,
+        name: &Name,
+        query_type: RecordType,
+        response_handle: R,
+    ) -> Result<ResponseInfo, DnsError> {
+        let response_builder = MessageResponseBuilder::from_message_request(request);
+        let identity = [
+            "version.bind.",
+            "version.server.",
+            "hostname.bind.",
+            "id.server.",
+        ]
+        .iter()
+        .any(|known| name.to_ascii().eq_ignore_ascii_case(known));
+        let (true, RecordType::TXT, Some(version)) =
+            (identity, query_type, &self.options.server_version)
+        else {
+            return self
+                .send_response(
+                    response_edns,
+                    response_builder.error_msg(request.header(), ResponseCode::Refused),
+                    response_handle,
+                )
+                .await;
+        };
+        let mut record =
+            Record::from_rdata(name.clone(), 0, RData::TXT(TXT::new(vec![version.clone()])));
+        record.set_dns_class(DNSClass::CH);
+        self.send_response(
+            response_edns,
+            response_builder.build(Self::synthesized_header(request), &[record], &[], &[], &[]),
+            response_handle,
+        )
+        .await
+    }
+
+    async fn send_upstream_failure<R: ResponseHandler>(
+        &self,
+        response_edns: Option<Edns>,
+        request: &Request,
+        name: &str,
+        e: anyhow::Error,
+        response_handle: R,
+    ) -> Result<ResponseInfo, DnsError> {
+        let e = DnsError::upstream(e);
+        // Anything we can't place is left to the catch-all in handle_request
+        if let DnsError::Internal(_) = e {
+            return Err(e);
+        }
+        log::warn!("Failed to resolve {name}: {e}");
+        self.send_response(
+            response_edns,
+            MessageResponseBuilder::from_message_request(request)
+                .error_msg(request.header(), e.response_code()),
+            response_handle,
+        )
+        .await
+    }
+
+    async fn send_blocked<R: ResponseHandler>(
+        &self,
+        response_edns: Option<Edns>,
+        request: &Request,
+        name: &Name,
+        query_type: RecordType,
+        rule: &str,
+        response_handle: R,
+    ) -> Result<ResponseInfo, DnsError> {
+        let response_builder = MessageResponseBuilder::from_message_request(request);
+        let mut response_header = Self::synthesized_header(request);
+        let records = match self.options.block_mode {
+            BlockMode::NxDomain => {
+                response_header.set_response_code(ResponseCode::NXDomain);
+                vec![]
+            }
+            BlockMode::Refused => {
+                return self
+                    .send_response(
+                        response_edns,
+                        response_builder.error_msg(request.header(), ResponseCode::Refused),
+                        response_handle,
+                    )
+                    .await;
+            }
+            // Non-address types get an empty NoError (NODATA) answer
+            BlockMode::Sinkhole => self.sinkhole_records(name, query_type),
+        };
+        let soa = if records.is_empty() {
+            self.negative_soa(name)
+        } else {
+            vec![]
+        };
+        // Lets `dig` show which entry caught the name
+        let explanation = if self.options.block_explain {
+            vec![Record::from_rdata(
+                name.clone(),
+                0,
+                RData::TXT(TXT::new(vec![format!("blocked by {rule}")])),
+            )]
+        } else {
+            vec![]
+        };
+        self.send_response(
+            response_edns,
+            response_builder.build(response_header, &records, &soa, &[], &explanation),
+            response_handle,
+        )
+        .await
+    }
+
+    fn is_client_allowed(&self, client: IpAddr) -> bool {
+        // Dual-stack sockets report IPv4 clients as IPv4-mapped IPv6 addresses
+        let client = client.to_canonical();
+        self.options.allowed_clients.is_empty()
+            || self
+                .options
+                .allowed_clients
+                .iter()
+                .any(|net| net.contains(&client))
+    }
+
+    // A stream whose peer stopped reading can stall the send forever, holding the query task
     async fn send_response<'a, R: ResponseHandler>(
+        &self,
         response_edns: Option<Edns>,
         mut response: MessageResponse<
             '_,
@@ -133,37 +1689,148 @@ impl DnsHandler {
             impl Iterator<Item = &'a Record> + Send + 'a,
         >,
         mut response_handle: R,
-    ) -> anyhow::Result<ResponseInfo> {
+    ) -> Result<ResponseInfo, DnsError> {
         if let Some(resp_edns) = response_edns {
             response.set_edns(resp_edns);
         }
 
-        Ok(response_handle.send_response(response).await?)
+        let timeout = self.options.response_send_timeout;
+        match tokio::time::timeout(timeout, response_handle.send_response(response)).await {
+            Ok(sent) => Ok(sent?),
+            Err(_) => Err(DnsError::Send(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("abandoned after {}ms", timeout.as_millis()),
+            ))),
+        }
     }
+    #[tracing::instrument(
+        name = "query",
+        skip_all,
+        fields(qname, qtype, blocked, cache_hit, upstream_ms)
+    )]
     async fn try_handle_request<R: ResponseHandler>(
         &self,
         request: &Request,
-        mut response_handle: R,
-    ) -> anyhow::Result<ResponseInfo> {
-        request.request_info()?;
+        response_handle: R,
+    ) -> Result<ResponseInfo, DnsError> {
+        // The source address is known without parsing anything, so denied and throttled clients
+        // get no further, not even an error for a garbage packet
+        if !self.is_client_allowed(request.src().ip()) {
+            log::debug!("Refused query from {}", request.src().ip());
+            return self
+                .send_response(
+                    None,
+                    MessageResponseBuilder::from_message_request(request)
+                        .error_msg(request.header(), ResponseCode::Refused),
+                    response_handle,
+                )
+                .await;
+        }
+        if let Some(limiter) = &self.rate_limiter
+            && !limiter.check(request.src().ip())
+        {
+            log::debug!("Rate limited query from {}", request.src().ip());
+            if limiter.action() == RateLimitAction::Drop {
+                let mut header = Header::response_from_request(request.header());
+                header.set_response_code(ResponseCode::Refused);
+                return Ok(header.into());
+            }
+            return self
+                .send_response(
+                    None,
+                    MessageResponseBuilder::from_message_request(request)
+                        .error_msg(request.header(), ResponseCode::Refused),
+                    response_handle,
+                )
+                .await;
+        }
+        let request_info = match request.request_info() {
+            Ok(request_info) => request_info,
+            Err(e) => {
+                log::debug!("Malformed request from {}: {e}", request.src());
+                let response_code = match self.options.malformed_policy {
+                    MalformedPolicy::Drop => {
+                        let mut header = Header::response_from_request(request.header());
+                        header.set_response_code(ResponseCode::FormErr);
+                        return Ok(header.into());
+                    }
+                    MalformedPolicy::FormErr => ResponseCode::FormErr,
+                    MalformedPolicy::ServFail => ResponseCode::ServFail,
+                };
+                return self
+                    .send_response(
+                        None,
+                        MessageResponseBuilder::from_message_request(request)
+                            .error_msg(request.header(), response_code),
+                        response_handle,
+                    )
+                    .await;
+            }
+        };
+        // Only reached past the ACL and the rate limit, so long names can't dodge either. len()
+        // counts a dot per label, the root label's byte makes it the wire length.
+        let qname = request_info.query.name();
+        if qname.len() + 1 > self.options.max_qname_len
+            || qname.num_labels() as usize > self.options.max_labels
+        {
+            log::debug!(
+                "Rejected over-long name {qname} from {}",
+                request.src().ip()
+            );
+            return self
+                .send_response(
+                    None,
+                    MessageResponseBuilder::from_message_request(request)
+                        .error_msg(request.header(), ResponseCode::FormErr),
+                    response_handle,
+                )
+                .await;
+        }
         let response_edns = match request.edns() {
             Some(req_edns) => {
-                let mut response = MessageResponseBuilder::from_message_request(request);
+                let response = MessageResponseBuilder::from_message_request(request);
                 let mut response_header = Header::response_from_request(request.header());
 
                 let mut resp_edns = Edns::new();
                 resp_edns.set_dnssec_ok(true);
-                resp_edns.set_max_payload(req_edns.max_payload().max(512));
+                let max_payload = req_edns.max_payload().max(512);
+                resp_edns.set_max_payload(match request.protocol() {
+                    Protocol::Udp => max_payload.min(self.options.max_udp_payload),
+                    _ => max_payload,
+                });
                 resp_edns.set_version(Self::OLD_VERSION);
 
                 if req_edns.version() > Self::OLD_VERSION {
                     response_header.set_response_code(ResponseCode::BADVERS);
                     resp_edns.set_rcode_high(ResponseCode::BADVERS.high());
-                    response.edns(resp_edns);
 
-                    return Ok(response_handle
-                        .send_response(response.build_no_records(response_header))
-                        .await?);
+                    return self
+                        .send_response(
+                            Some(resp_edns),
+                            response.build_no_records(response_header),
+                            response_handle,
+                        )
+                        .await;
+                }
+
+                // Clients that don't send a cookie get none back
+                if let Some(secret) = &self.cookie_secret
+                    && let Some(EdnsOption::Unknown(_, option)) =
+                        req_edns.options().get(EdnsCode::Cookie)
+                {
+                    let Some(cookie) = secret.respond(option, request.src().ip()) else {
+                        response_header.set_response_code(ResponseCode::FormErr);
+                        return self
+                            .send_response(
+                                Some(resp_edns),
+                                response.build_no_records(response_header),
+                                response_handle,
+                            )
+                            .await;
+                    };
+                    resp_edns
+                        .options_mut()
+                        .insert(EdnsOption::Unknown(u16::from(EdnsCode::Cookie), cookie));
                 }
                 Some(resp_edns)
             }
@@ -177,7 +1844,7 @@ impl DnsHandler {
             }
             _ => {
                 let response = MessageResponseBuilder::from_message_request(request);
-                Self::send_response(
+                self.send_response(
                     response_edns,
                     response.error_msg(request.header(), ResponseCode::NotImp),
                     response_handle,
@@ -199,9 +1866,57 @@ impl RequestHandler for DnsHandler {
             .await
             .unwrap_or_else(|e| {
                 log::warn!("Error handling request: {e}");
-                let mut header = Header::new(0, MessageType::Query, OpCode::Query);
-                header.set_response_code(ResponseCode::ServFail);
+                let mut header = Header::response_from_request(request.header());
+                header.set_response_code(e.response_code());
                 header.into()
             })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn minimized_name_keeps_registrable_domain() {
+        assert_eq!(minimized_name("a.b.example.com."), "example.com.");
+        assert_eq!(minimized_name("example.com."), "example.com.");
+        assert_eq!(minimized_name("localhost."), "localhost.");
+        assert_eq!(minimized_name("."), ".");
+    }
+
+    #[test]
+    fn client_suffix_formats_both_families() {
+        assert_eq!(
+            client_suffix("192.0.2.7".parse().unwrap()),
+            " from 192.0.2.7"
+        );
+        assert_eq!(
+            client_suffix("2001:db8::7".parse().unwrap()),
+            " from 2001:db8::7"
+        );
+        // IPv4 clients on a dual-stack socket
+        assert_eq!(
+            client_suffix("::ffff:192.0.2.7".parse().unwrap()),
+            " from 192.0.2.7"
+        );
+    }
+
+    #[test]
+    fn embedded_ipv4_skips_the_reserved_octet() {
+        let ip = Ipv4Addr::new(192, 0, 2, 33);
+        // RFC 6052 2.4 examples
+        for (prefix, expected) in [
+            ("64:ff9b::/96", "64:ff9b::c000:221"),
+            ("2001:db8::/32", "2001:db8:c000:221::"),
+            ("2001:db8:100::/40", "2001:db8:1c0:2:21::"),
+            ("2001:db8:122:344::/64", "2001:db8:122:344:c0:2:2100:0"),
+        ] {
+            assert_eq!(
+                DnsHandler::embed_ipv4(prefix.parse().unwrap(), ip),
+                expected.parse::<Ipv6Addr>().unwrap(),
+                "{prefix}"
+            );
+        }
+    }
+}
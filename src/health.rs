@@ -0,0 +1,77 @@
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{Router, extract::State, http::StatusCode, routing::get};
+use hickory_proto::rr::Name;
+use tokio::{net::TcpListener, time::MissedTickBehavior};
+
+use crate::dns::DnsHandler;
+
+pub struct Health {
+    upstream_connections: AtomicUsize,
+    last_probe: Mutex<Option<Instant>>,
+    max_probe_age: Duration,
+}
+
+impl Health {
+    pub fn new(upstream_connections: usize, probe_interval: Duration) -> Self {
+        Self {
+            upstream_connections: AtomicUsize::new(upstream_connections),
+            last_probe: Mutex::new(None),
+            // Tolerate a couple of slow or lost probes before reporting unhealthy
+            max_probe_age: probe_interval * 3,
+        }
+    }
+    pub fn upstream_closed(&self) {
+        self.upstream_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+    pub fn upstream_reconnected(&self) {
+        self.upstream_connections.fetch_add(1, Ordering::Relaxed);
+    }
+    fn is_healthy(&self) -> bool {
+        self.upstream_connections.load(Ordering::Relaxed) > 0
+            && self
+                .last_probe
+                .lock()
+                .unwrap()
+                .is_some_and(|last| last.elapsed() <= self.max_probe_age)
+    }
+}
+
+pub async fn probe_periodically(
+    handler: DnsHandler,
+    health: Arc<Health>,
+    name: Name,
+    interval: Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        match handler.probe(name.clone()).await {
+            Ok(()) => *health.last_probe.lock().unwrap() = Some(Instant::now()),
+            Err(e) => log::warn!("Health probe for {name} failed: {e}"),
+        }
+    }
+}
+
+async fn health(State(health): State<Arc<Health>>) -> StatusCode {
+    if health.is_healthy() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+pub async fn serve(listener: TcpListener, health: Arc<Health>) -> anyhow::Result<()> {
+    let router = Router::new()
+        .route("/health", get(self::health))
+        .with_state(health);
+    axum::serve(listener, router).await?;
+    Ok(())
+}
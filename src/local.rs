@@ -0,0 +1,214 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use fxhash::FxHashMap;
+use hickory_proto::rr::{
+    Name, RData, Record, RecordType,
+    rdata::{A, AAAA, CNAME, PTR},
+};
+
+use crate::blocklist::strip_comment;
+
+pub enum LocalAnswer {
+    Records(Vec<Record>),
+    // A CNAME chain ending at a name that has to be resolved upstream
+    Forward(Vec<Record>, Name),
+}
+
+#[derive(Clone, Default)]
+pub struct LocalRecords {
+    addrs: FxHashMap<Name, Vec<IpAddr>>,
+    cnames: FxHashMap<Name, Name>,
+    ptrs: FxHashMap<Name, Name>,
+}
+
+impl LocalRecords {
+    const TTL: u32 = 300;
+    const MAX_CNAME_DEPTH: usize = 8;
+
+    fn parse_name(name: &str) -> anyhow::Result<Name> {
+        Ok(Name::from_ascii(name)?.append_domain(&Name::root())?)
+    }
+    pub fn parse(text: &str, with_ptr: bool) -> anyhow::Result<Self> {
+        let mut records = Self::default();
+        for (i, line) in text.lines().enumerate() {
+            let fields = strip_comment(line).split_whitespace().collect::<Vec<_>>();
+            match fields.as_slice() {
+                [] => continue,
+                [alias, kind, target] if kind.eq_ignore_ascii_case("CNAME") => {
+                    records
+                        .cnames
+                        .insert(Self::parse_name(alias)?, Self::parse_name(target)?);
+                }
+                [ip, hosts @ ..] => {
+                    let ip = ip
+                        .parse::<IpAddr>()
+                        .map_err(|_| anyhow::anyhow!("Invalid address on line {}: {ip}", i + 1))?;
+                    for host in hosts {
+                        let name = Self::parse_name(host)?;
+                        // The first name listed for an address is the one PTR queries resolve to
+                        if with_ptr {
+                            records
+                                .ptrs
+                                .entry(Name::from(ip))
+                                .or_insert_with(|| name.clone());
+                        }
+                        records.addrs.entry(name).or_default().push(ip);
+                    }
+                }
+            }
+        }
+        Ok(records)
+    }
+    // REWRITE_MAP entries are `name=address` or `name=target`, the latter answered as a CNAME
+    pub fn from_rewrites(entries: &[String]) -> anyhow::Result<Self> {
+        let mut records = Self::default();
+        for entry in entries {
+            let (name, target) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("Invalid REWRITE_MAP entry: {entry}"))?;
+            let name = Self::parse_name(name.trim())?;
+            match target.trim().parse::<IpAddr>() {
+                Ok(ip) => records.addrs.entry(name).or_default().push(ip),
+                Err(_) => {
+                    records
+                        .cnames
+                        .insert(name, Self::parse_name(target.trim())?);
+                }
+            }
+        }
+        Ok(records)
+    }
+    // Private, loopback and link-local addresses, which never resolve publicly
+    pub fn is_private(ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+            IpAddr::V6(ip) => {
+                ip.is_loopback() || ip.is_unique_local() || ip.is_unicast_link_local()
+            }
+        }
+    }
+    pub fn is_private_reverse(name: &Name) -> bool {
+        name.parse_arpa_name()
+            .is_ok_and(|net| Self::is_private(net.addr()))
+    }
+    // RFC 6761 section 6.3: localhost and every name under it is loopback, and the loopback
+    // addresses point back at localhost
+    pub fn special_use(name: &Name, query_type: RecordType) -> Option<LocalAnswer> {
+        let localhost = Name::from_ascii("localhost.").unwrap();
+        if localhost.zone_of(name) {
+            let rdata = match query_type {
+                RecordType::A => Some(RData::A(A(Ipv4Addr::LOCALHOST))),
+                RecordType::AAAA => Some(RData::AAAA(AAAA(Ipv6Addr::LOCALHOST))),
+                _ => None,
+            };
+            return Some(LocalAnswer::Records(
+                rdata
+                    .map(|rdata| Record::from_rdata(name.clone(), Self::TTL, rdata))
+                    .into_iter()
+                    .collect(),
+            ));
+        }
+        let loopback = [
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ];
+        if !loopback.into_iter().any(|ip| Name::from(ip) == *name) {
+            return None;
+        }
+        Some(LocalAnswer::Records(match query_type {
+            RecordType::PTR => vec![Record::from_rdata(
+                name.clone(),
+                Self::TTL,
+                RData::PTR(PTR(localhost)),
+            )],
+            _ => vec![],
+        }))
+    }
+    pub fn len(&self) -> usize {
+        self.addrs.len() + self.cnames.len()
+    }
+    fn address_records(&self, name: &Name, query_type: RecordType) -> Option<Vec<Record>> {
+        let addrs = self.addrs.get(name)?;
+        Some(
+            addrs
+                .iter()
+                .filter_map(|ip| match (ip, query_type) {
+                    (IpAddr::V4(ip), RecordType::A) => Some(RData::A(A(*ip))),
+                    (IpAddr::V6(ip), RecordType::AAAA) => Some(RData::AAAA(AAAA(*ip))),
+                    _ => None,
+                })
+                .map(|rdata| Record::from_rdata(name.clone(), Self::TTL, rdata))
+                .collect(),
+        )
+    }
+    // None means the name isn't local, an empty answer is NODATA
+    pub fn lookup(&self, name: &Name, query_type: RecordType) -> Option<LocalAnswer> {
+        if let Some(records) = self.address_records(name, query_type) {
+            return Some(LocalAnswer::Records(records));
+        }
+        if let Some(host) = self.ptrs.get(name) {
+            return Some(LocalAnswer::Records(match query_type {
+                RecordType::PTR => vec![Record::from_rdata(
+                    name.clone(),
+                    Self::TTL,
+                    RData::PTR(PTR(host.clone())),
+                )],
+                _ => vec![],
+            }));
+        }
+        let mut target = self.cnames.get(name)?;
+        let mut chain = vec![Record::from_rdata(
+            name.clone(),
+            Self::TTL,
+            RData::CNAME(CNAME(target.clone())),
+        )];
+        if query_type == RecordType::CNAME {
+            return Some(LocalAnswer::Records(chain));
+        }
+        loop {
+            if let Some(records) = self.address_records(target, query_type) {
+                chain.extend(records);
+                return Some(LocalAnswer::Records(chain));
+            }
+            let Some(next) = self.cnames.get(target) else {
+                return Some(LocalAnswer::Forward(chain, target.clone()));
+            };
+            if chain.len() >= Self::MAX_CNAME_DEPTH {
+                log::warn!(
+                    "Local CNAME chain for {name} exceeds {} links",
+                    Self::MAX_CNAME_DEPTH
+                );
+                return Some(LocalAnswer::Records(chain));
+            }
+            chain.push(Record::from_rdata(
+                target.clone(),
+                Self::TTL,
+                RData::CNAME(CNAME(next.clone())),
+            ));
+            target = next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_records_follow_the_blocklist_comment_rule() {
+        let records = LocalRecords::parse(
+            "# hosts\n10.0.0.5 nas.home # 10.0.0.6 printer.home\n  #10.0.0.7 old.home\n\
+         10.0.0.8 tv.home\t# living room",
+            false,
+        )
+        .unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(
+            records
+                .lookup(&Name::from_ascii("printer.home.").unwrap(), RecordType::A)
+                .is_none()
+        );
+        // Only whitespace in front makes `#` a comment, so this is one malformed host name
+        assert!(LocalRecords::parse("10.0.0.5 nas.home#old", false).is_err());
+    }
+}
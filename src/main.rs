@@ -1,43 +1,486 @@
+use clap::{Parser, builder::BoolishValueParser};
 use dotenvy::dotenv;
+use fxhash::FxHashMap;
 use hickory_server::Server;
 use log::LevelFilter;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{task::JoinSet, time::MissedTickBehavior};
 
+mod admin;
+mod blocklist;
+mod cache;
 mod config;
+mod cookie;
 mod dns;
+mod health;
+mod local;
+mod metrics;
+mod querylog;
+mod ratelimit;
+mod telemetry;
+#[cfg(test)]
+mod tests;
 
-async fn main_inner() -> anyhow::Result<()> {
-    let conf = config::Configure::new()?;
-    let blocklist = conf.build_blocklist().await?;
-    let (upstream, upstream_handle) = conf.spawn_upstream().await?;
-    let handler = dns::DnsHandler::new(Arc::new(Mutex::new(upstream)), blocklist);
+#[derive(Parser)]
+#[command(
+    version,
+    about = "A blocking DNS forwarder",
+    after_help = "Every setting can also be given as an environment variable or in the CONFIG_PATH file; flags take precedence."
+)]
+struct Args {
+    #[arg(long, value_name = "PATH", help = "TOML config file (CONFIG_PATH)")]
+    config: Option<String>,
+    #[arg(
+        long,
+        value_name = "KIND",
+        help = "udp, tcp, h3, quic, dot or https (UPSTREAM_KIND)"
+    )]
+    upstream_kind: Option<String>,
+    #[arg(
+        long,
+        value_name = "ADDR,...",
+        help = "Upstream addresses (UPSTREAM_ADDR)"
+    )]
+    upstream_addr: Option<String>,
+    #[arg(
+        long,
+        value_name = "URI",
+        help = "Upstream URI for h3/quic/dot (UPSTREAM_URI)"
+    )]
+    upstream_uri: Option<String>,
+    #[arg(
+        long,
+        value_name = "NAME",
+        help = "TLS server name for dot (UPSTREAM_TLS_NAME)"
+    )]
+    upstream_tls_name: Option<String>,
+    #[arg(
+        long,
+        value_name = "ADDR,...",
+        help = "UDP listen addresses (BIND_UDP_ADDR)"
+    )]
+    bind_udp_addr: Option<String>,
+    #[arg(
+        long,
+        value_name = "ADDR,...",
+        help = "Enable H3 on these addresses (BIND_H3_ADDR)"
+    )]
+    bind_h3_addr: Option<String>,
+    #[arg(
+        long,
+        value_name = "ADDR,...",
+        help = "Enable QUIC on these addresses (BIND_QUIC_ADDR)"
+    )]
+    bind_quic_addr: Option<String>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Listener certificate chain (BIND_CERT_PATH)"
+    )]
+    bind_cert: Option<String>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Listener private key (BIND_PRIVATE_KEY_PATH)"
+    )]
+    bind_private_key: Option<String>,
+    #[arg(
+        long,
+        value_name = "PATH,...",
+        help = "Blocklist files or URLs (BLOCKLIST_PATH)"
+    )]
+    blocklist: Option<String>,
+    #[arg(
+        long,
+        value_name = "PATH,...",
+        help = "Allowlist files or URLs (ALLOWLIST_PATH)"
+    )]
+    allowlist: Option<String>,
+    #[arg(
+        long,
+        value_name = "MODE",
+        help = "nxdomain, refused or sinkhole (BLOCK_MODE)"
+    )]
+    block_mode: Option<String>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Local hosts file (LOCAL_HOSTS_PATH)"
+    )]
+    local_hosts: Option<String>,
+    #[arg(long, value_name = "PATH", help = "JSON query log (QUERY_LOG_PATH)")]
+    query_log: Option<String>,
+    #[arg(long, value_name = "ADDR", help = "Prometheus listener (METRICS_ADDR)")]
+    metrics_addr: Option<String>,
+    #[arg(long, value_name = "ADDR", help = "Admin API listener (ADMIN_ADDR)")]
+    admin_addr: Option<String>,
+    #[arg(
+        long,
+        value_name = "ADDR",
+        help = "Health check listener (HEALTH_ADDR)"
+    )]
+    health_addr: Option<String>,
+    #[arg(
+        long,
+        env = "CONFIG_CHECK",
+        value_parser = BoolishValueParser::new(),
+        help = "Validate the configuration and exit without binding or connecting upstream"
+    )]
+    check: bool,
+    #[arg(
+        long,
+        requires = "check",
+        help = "Also connect to the upstreams in check mode"
+    )]
+    check_upstream: bool,
+    #[arg(
+        long = "set",
+        value_name = "KEY=VALUE",
+        help = "Any other setting by its environment variable name"
+    )]
+    settings: Vec<String>,
+}
+
+impl Args {
+    fn into_settings(self) -> anyhow::Result<FxHashMap<String, String>> {
+        let mut settings = FxHashMap::default();
+        for setting in self.settings {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or(anyhow::anyhow!("Invalid --set value: {setting}"))?;
+            settings.insert(key.trim().to_ascii_uppercase(), value.to_string());
+        }
+        // A listener address given on the command line also turns the listener on
+        if self.bind_h3_addr.is_some() {
+            settings.insert("BIND_H3".to_string(), "true".to_string());
+        }
+        if self.bind_quic_addr.is_some() {
+            settings.insert("BIND_QUIC".to_string(), "true".to_string());
+        }
+        for (key, value) in [
+            ("CONFIG_PATH", self.config),
+            ("UPSTREAM_KIND", self.upstream_kind),
+            ("UPSTREAM_ADDR", self.upstream_addr),
+            ("UPSTREAM_URI", self.upstream_uri),
+            ("UPSTREAM_TLS_NAME", self.upstream_tls_name),
+            ("BIND_UDP_ADDR", self.bind_udp_addr),
+            ("BIND_H3_ADDR", self.bind_h3_addr),
+            ("BIND_QUIC_ADDR", self.bind_quic_addr),
+            ("BIND_CERT_PATH", self.bind_cert),
+            ("BIND_PRIVATE_KEY_PATH", self.bind_private_key),
+            ("BLOCKLIST_PATH", self.blocklist),
+            ("ALLOWLIST_PATH", self.allowlist),
+            ("BLOCK_MODE", self.block_mode),
+            ("LOCAL_HOSTS_PATH", self.local_hosts),
+            ("QUERY_LOG_PATH", self.query_log),
+            ("METRICS_ADDR", self.metrics_addr),
+            ("ADMIN_ADDR", self.admin_addr),
+            ("HEALTH_ADDR", self.health_addr),
+        ] {
+            if let Some(value) = value {
+                settings.insert(key.to_string(), value);
+            }
+        }
+        Ok(settings)
+    }
+}
+
+async fn reload_lists(conf: &config::Configure, handler: &dns::DnsHandler) -> anyhow::Result<()> {
+    let started = Instant::now();
+    let Some((blocklist, allowlist)) = conf.reload_lists().await? else {
+        log::info!("Lists unchanged, keeping the current ones");
+        return Ok(());
+    };
+    let (blocked, allowed) = (blocklist.len(), allowlist.len());
+    handler.set_lists(blocklist, allowlist).await;
+    log::info!(
+        "Reloaded {} blocklist and {} allowlist entries in {:?}",
+        blocked,
+        allowed,
+        started.elapsed()
+    );
+    Ok(())
+}
+
+async fn reload_periodically(
+    conf: Arc<config::Configure>,
+    handler: dns::DnsHandler,
+    interval: Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        if let Err(e) = reload_lists(&conf, &handler).await {
+            log::warn!("Failed to reload lists: {e:#}");
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn reload_on_hangup(
+    conf: Arc<config::Configure>,
+    handler: dns::DnsHandler,
+    mut hangup: tokio::signal::unix::Signal,
+) {
+    while hangup.recv().await.is_some() {
+        log::info!("Received SIGHUP, reloading lists");
+        if let Err(e) = reload_lists(&conf, &handler).await {
+            log::warn!("Failed to reload lists: {e:#}");
+        }
+    }
+}
+
+// Retried until it works, with the delay doubling up to a minute. Even the first attempt waits, so
+// a connection that keeps dying right away doesn't turn into a reconnect loop. Runs as a task of
+// its own and then lasts as long as the new connection does, so one upstream staying down never
+// holds up reconnecting the others.
+async fn reconnect_upstream(
+    conf: Arc<config::Configure>,
+    handler: dns::DnsHandler,
+    health: Arc<health::Health>,
+    task: config::UpstreamTask,
+) -> anyhow::Result<()> {
+    const MAX_DELAY: Duration = Duration::from_secs(60);
+    let mut delay = Duration::from_secs(1);
+    let mut tasks = config::UpstreamTasks::default();
+    loop {
+        tokio::time::sleep(delay).await;
+        log::info!("Reconnecting to upstream {}", task.addr);
+        match conf.reconnect_upstream(&task, &mut tasks).await {
+            Ok(client) => {
+                handler
+                    .replace_upstream_client(
+                        task.zone.as_ref(),
+                        &task.addr,
+                        task.tcp_fallback,
+                        client,
+                    )
+                    .await;
+                log::info!("Reconnected to upstream {}", task.addr);
+                if !task.tcp_fallback {
+                    health.upstream_reconnected();
+                }
+                break;
+            }
+            Err(e) => log::warn!("Failed to reconnect to upstream {}: {e:#}", task.addr),
+        }
+        delay = (delay * 2).min(MAX_DELAY);
+    }
+    match tasks.join_next().await {
+        Some((_, result)) => result,
+        None => Ok(()),
+    }
+}
+
+// Set up front, so a signal that can't be registered fails startup instead of stopping the server
+fn shutdown_signal() -> anyhow::Result<impl Future<Output = ()>> {
+    #[cfg(unix)]
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    Ok(async move {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+        #[cfg(not(unix))]
+        let _ = tokio::signal::ctrl_c().await;
+    })
+}
+
+async fn main_inner(args: Args) -> anyhow::Result<()> {
+    let (check, check_upstream) = (args.check, args.check_upstream);
+    let conf = Arc::new(config::Configure::from_args(args.into_settings()?)?);
+    if check {
+        return conf.check(check_upstream).await;
+    }
+    let tracer_provider = conf.otlp_endpoint().map(telemetry::init).transpose()?;
+    let allowlist = conf.build_allowlist().await?;
+    let blocklist = conf.build_blocklist(&allowlist).await?;
+    let (upstreams, forward_zones, mut upstream_tasks) = conf.spawn_upstream().await?;
+    let metrics = Arc::new(metrics::Metrics::new()?);
+    let handler = dns::DnsHandler::builder(conf.handler_options(), metrics.clone())
+        .upstreams(upstreams)
+        .forward_zones(forward_zones)
+        .blocklist(blocklist)
+        .allowlist(allowlist)
+        .query_log(conf.open_query_log().await?)
+        .build();
+    if let Some(interval) = conf.blocklist_reload_interval() {
+        tokio::spawn(reload_periodically(conf.clone(), handler.clone(), interval));
+    }
+    if let Some(interval) = conf.stats_interval() {
+        tokio::spawn(metrics::log_periodically(
+            handler.clone(),
+            metrics.clone(),
+            interval,
+        ));
+    }
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        let hangup = signal(SignalKind::hangup())?;
+        tokio::spawn(reload_on_hangup(conf.clone(), handler.clone(), hangup));
+    }
+    let admin_handler = handler.clone();
+    let (health_check_name, health_check_interval) = conf.health_check();
+    let health = Arc::new(health::Health::new(
+        upstream_tasks.primary_len(),
+        health_check_interval,
+    ));
+    let health_listener = conf.bind_health().await?;
+    if health_listener.is_some() {
+        tokio::spawn(health::probe_periodically(
+            handler.clone(),
+            health.clone(),
+            health_check_name,
+            health_check_interval,
+        ));
+    }
+    if let Some(path) = conf.cache_snapshot_path() {
+        handler.load_cache_snapshot(path).await;
+    }
+    if let Some(path) = conf.decision_cache_path() {
+        handler.load_decision_cache(path).await;
+    }
+    // Primed before the sockets are registered, so no client ever sees the cold cache
+    let warmup = conf.read_cache_warmup().await?;
+    if !warmup.is_empty() {
+        handler.warm_up(warmup).await;
+    }
+    let snapshot_handler = handler.clone();
     let mut server = Server::new(handler);
     conf.register_sockets(&mut server).await?;
+    let mut http_servers = JoinSet::new();
+    let admin_metrics = metrics.clone();
+    if let Some(listener) = conf.bind_metrics().await? {
+        http_servers.spawn(async move { ("Metrics", metrics::serve(listener, metrics).await) });
+    }
+    if let Some(listener) = conf.bind_admin().await? {
+        http_servers.spawn(async move {
+            (
+                "Admin",
+                admin::serve(listener, admin_handler, admin_metrics).await,
+            )
+        });
+    }
+    if let Some(listener) = health_listener {
+        let health = health.clone();
+        http_servers.spawn(async move { ("Health", health::serve(listener, health).await) });
+    }
+    let http_stopped = async move {
+        match http_servers.join_next().await {
+            Some(result) => result,
+            None => std::future::pending().await,
+        }
+    };
     let server_handle = server.block_until_done();
+    let shutdown = shutdown_signal()?;
+    let (upstream_conf, upstream_handler) = (conf.clone(), snapshot_handler.clone());
+    let upstreams_closed = async move {
+        while let Some((task, result)) = upstream_tasks.join_next().await {
+            if !task.tcp_fallback {
+                health.upstream_closed();
+            }
+            match result {
+                Ok(()) => log::warn!("Upstream {} connection closed", task.addr),
+                Err(e) => log::warn!("Upstream {} connection failed: {e:#}", task.addr),
+            }
+            let reconnect = reconnect_upstream(
+                upstream_conf.clone(),
+                upstream_handler.clone(),
+                health.clone(),
+                task.clone(),
+            );
+            upstream_tasks.spawn(&task, reconnect);
+        }
+    };
     tokio::select! {
-        _ = upstream_handle => {
-            log::error!("Upstream client connection closed unexpectedly.");
+        _ = upstreams_closed => {
+            log::error!("All upstream client connections closed unexpectedly.");
+        }
+        result = http_stopped => {
+            match result {
+                Ok((name, Ok(()))) => log::error!("{name} server stopped unexpectedly."),
+                Ok((name, Err(e))) => log::error!("{name} server failed: {e:#}"),
+                Err(e) => log::error!("HTTP server task failed: {e}"),
+            }
         }
         _ = server_handle => {
             log::info!("DNS server stopped.");
         }
+        _ = shutdown => {
+            log::info!("Received shutdown signal, stopping.");
+        }
+    }
+    if let Some(path) = conf.cache_snapshot_path()
+        && let Err(e) = snapshot_handler.save_cache_snapshot(path).await
+    {
+        log::warn!("Failed to save cache snapshot to {path}: {e:#}");
+    }
+    if let Some(path) = conf.decision_cache_path()
+        && let Err(e) = snapshot_handler.save_decision_cache(path).await
+    {
+        log::warn!("Failed to save decision cache to {path}: {e:#}");
+    }
+    // Flush the spans still waiting in the batch
+    if let Some(provider) = tracer_provider
+        && let Err(e) = provider.shutdown()
+    {
+        log::warn!("Failed to flush traces: {e}");
     }
     Ok(())
 }
 
 #[tokio::main]
 async fn main() {
-    #[cfg(debug_assertions)]
-    let log_level = LevelFilter::Debug;
-    #[cfg(not(debug_assertions))]
-    let log_level = LevelFilter::Info;
-    dotenv().ok();
+    // Without NDNS_LOG_LEVEL debug builds keep logging at debug, release builds at info
+    let default_level = if cfg!(debug_assertions) {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    };
+    // Instances sharing a directory each point NDNS_ENV_FILE at their own file
+    let env_file = match std::env::var("NDNS_ENV_FILE") {
+        Ok(path) => dotenvy::from_path(&path)
+            .map(|()| Some(PathBuf::from(&path)))
+            .map_err(|e| format!("Failed to load NDNS_ENV_FILE {path}: {e}")),
+        Err(_) => Ok(dotenv().ok()),
+    };
+    // Parsed only now, so clap's `env` fallbacks see what the env file set too
+    let args = Args::parse();
+    // Read straight from the environment, the logger has to exist before the config is loaded
+    let (log_level, invalid_level) = match std::env::var("NDNS_LOG_LEVEL") {
+        Ok(level) => match level.parse::<LevelFilter>() {
+            Ok(log_level) => (log_level, None),
+            Err(_) => (default_level, Some(level)),
+        },
+        Err(_) => (default_level, None),
+    };
     env_logger::builder()
         .filter_level(LevelFilter::Warn)
         .filter(Some("ndns"), log_level)
         .init();
-    if let Err(e) = main_inner().await {
-        log::error!("Error occurred: {e}");
+    match env_file {
+        Ok(Some(path)) => log::info!("Loaded environment from {}", path.display()),
+        Ok(None) => {}
+        Err(e) => {
+            log::error!("{e}");
+            std::process::exit(1);
+        }
+    }
+    if let Some(level) = invalid_level {
+        log::warn!(
+            "Ignoring invalid NDNS_LOG_LEVEL {level:?}, expected trace, debug, info, warn or error"
+        );
+    }
+    if let Err(e) = main_inner(args).await {
+        log::error!("Error occurred: {e:#}");
+        std::process::exit(1);
     }
 }
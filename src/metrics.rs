@@ -0,0 +1,111 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::{cache::ShardedLru, dns::DnsHandler};
+use axum::{Router, extract::State, http::header, response::IntoResponse, routing::get};
+use prometheus::{Histogram, HistogramOpts, IntCounter, Registry, TEXT_FORMAT, TextEncoder};
+use serde::Serialize;
+use tokio::{net::TcpListener, time::MissedTickBehavior};
+
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct DomainCount {
+    pub total: u64,
+    pub blocked: u64,
+}
+
+pub type DomainStats = ShardedLru<String, DomainCount>;
+
+pub struct Metrics {
+    registry: Registry,
+    pub queries: IntCounter,
+    pub blocked: IntCounter,
+    pub upstream_errors: IntCounter,
+    pub upstream_timeouts: IntCounter,
+    pub cache_hits: IntCounter,
+    pub cache_misses: IntCounter,
+    pub upstream_latency: Histogram,
+}
+
+impl Metrics {
+    pub fn new() -> anyhow::Result<Self> {
+        let registry = Registry::new_custom(Some("ndns".to_string()), None)?;
+        let counter = |name: &str, help: &str| -> anyhow::Result<IntCounter> {
+            let counter = IntCounter::new(name, help)?;
+            registry.register(Box::new(counter.clone()))?;
+            Ok(counter)
+        };
+        let queries = counter("queries_total", "Total DNS queries received")?;
+        let blocked = counter("blocked_queries_total", "Queries answered as blocked")?;
+        let upstream_errors = counter("upstream_errors_total", "Failed upstream queries")?;
+        let upstream_timeouts =
+            counter("upstream_timeouts_total", "Upstream queries that timed out")?;
+        let cache_hits = counter("cache_hits_total", "Queries answered from the cache")?;
+        let cache_misses = counter("cache_misses_total", "Queries not found in the cache")?;
+        let upstream_latency = Histogram::with_opts(HistogramOpts::new(
+            "upstream_latency_seconds",
+            "Time spent forwarding a query upstream",
+        ))?;
+        registry.register(Box::new(upstream_latency.clone()))?;
+        Ok(Self {
+            registry,
+            queries,
+            blocked,
+            upstream_errors,
+            upstream_timeouts,
+            cache_hits,
+            cache_misses,
+            upstream_latency,
+        })
+    }
+    fn encode(&self) -> anyhow::Result<String> {
+        Ok(TextEncoder::new().encode_to_string(&self.registry.gather())?)
+    }
+}
+
+// The Prometheus counters keep running, the summary reports the difference since the previous line
+pub async fn log_periodically(handler: DnsHandler, metrics: Arc<Metrics>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticker.tick().await;
+    let mut last = [0; 5];
+    loop {
+        ticker.tick().await;
+        let current = [
+            metrics.queries.get(),
+            metrics.blocked.get(),
+            metrics.cache_hits.get(),
+            metrics.upstream_errors.get(),
+            metrics.upstream_timeouts.get(),
+        ];
+        let [
+            queries,
+            blocked,
+            cache_hits,
+            upstream_errors,
+            upstream_timeouts,
+        ] = std::array::from_fn(|i| current[i] - last[i]);
+        last = current;
+        let (cached_allow, cached_block) = handler.decision_cache_sizes();
+        log::info!(
+            target: "ndns::stats",
+            "queries={queries} blocked={blocked} cache_hits={cache_hits} upstream_errors={upstream_errors} upstream_timeouts={upstream_timeouts} cached_allow={cached_allow} cached_block={cached_block}"
+        );
+    }
+}
+
+async fn metrics(State(metrics): State<Arc<Metrics>>) -> impl IntoResponse {
+    match metrics.encode() {
+        Ok(body) => ([(header::CONTENT_TYPE, TEXT_FORMAT)], body).into_response(),
+        Err(e) => {
+            log::warn!("Failed to encode metrics: {e}");
+            axum::http::StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) -> anyhow::Result<()> {
+    let router = Router::new()
+        .route("/metrics", get(self::metrics))
+        .with_state(metrics);
+    axum::serve(listener, router).await?;
+    Ok(())
+}
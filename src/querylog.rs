@@ -0,0 +1,83 @@
+use std::{
+    net::IpAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncWriteExt, BufWriter},
+    sync::mpsc,
+};
+
+#[derive(Serialize)]
+pub struct QueryLogEntry {
+    pub timestamp_ms: u64,
+    pub client: IpAddr,
+    pub name: String,
+    pub qtype: String,
+    pub blocked: bool,
+    pub rcode: String,
+    pub upstream_ms: Option<f64>,
+}
+
+impl QueryLogEntry {
+    pub fn now_ms() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64)
+    }
+}
+
+pub struct QueryLog {
+    sender: mpsc::Sender<QueryLogEntry>,
+}
+
+impl QueryLog {
+    const QUEUE_SIZE: usize = 4096;
+    const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+    pub async fn open(path: &str) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        let (sender, receiver) = mpsc::channel(Self::QUEUE_SIZE);
+        let path = path.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = Self::write_entries(file, receiver).await {
+                log::error!("Query log {path} stopped: {e}");
+            }
+        });
+        Ok(Self { sender })
+    }
+    pub fn record(&self, entry: QueryLogEntry) {
+        // Never stall a query on the log writer; a full queue drops the entry
+        if self.sender.try_send(entry).is_err() {
+            log::debug!("Query log queue is full, dropping entry");
+        }
+    }
+    async fn write_entries(
+        file: File,
+        mut receiver: mpsc::Receiver<QueryLogEntry>,
+    ) -> anyhow::Result<()> {
+        let mut writer = BufWriter::new(file);
+        let mut flush = tokio::time::interval(Self::FLUSH_INTERVAL);
+        loop {
+            tokio::select! {
+                entry = receiver.recv() => {
+                    let Some(entry) = entry else {
+                        break;
+                    };
+                    let mut line = serde_json::to_vec(&entry)?;
+                    line.push(b'\n');
+                    writer.write_all(&line).await?;
+                }
+                _ = flush.tick() => writer.flush().await?,
+            }
+        }
+        writer.flush().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+use std::{net::IpAddr, time::Instant};
+
+use crate::cache::ShardedLru;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitAction {
+    Refused,
+    Drop,
+}
+
+impl std::str::FromStr for RateLimitAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "refused" => Ok(RateLimitAction::Refused),
+            "drop" => Ok(RateLimitAction::Drop),
+            _ => Err(anyhow::anyhow!("Invalid rate limit action: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct RateLimit {
+    pub qps: f64,
+    pub burst: f64,
+    pub action: RateLimitAction,
+    pub max_clients: usize,
+}
+
+#[derive(Default)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Option<Instant>,
+}
+
+pub struct RateLimiter {
+    // Idle clients fall out of the LRU, and a returning client starts with a full bucket anyway
+    buckets: ShardedLru<IpAddr, TokenBucket>,
+    limit: RateLimit,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            buckets: ShardedLru::new(limit.max_clients),
+            limit,
+        }
+    }
+    pub fn action(&self) -> RateLimitAction {
+        self.limit.action
+    }
+    pub fn check(&self, client: IpAddr) -> bool {
+        let now = Instant::now();
+        self.buckets
+            .update(&client, |bucket| {
+                bucket.tokens = match bucket.updated {
+                    Some(updated) => (bucket.tokens
+                        + (now - updated).as_secs_f64() * self.limit.qps)
+                        .min(self.limit.burst),
+                    None => self.limit.burst,
+                };
+                bucket.updated = Some(now);
+                if bucket.tokens < 1.0 {
+                    return false;
+                }
+                bucket.tokens -= 1.0;
+                true
+            })
+            .unwrap_or(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clients_over_the_rate_are_throttled_alone() {
+        let limiter = RateLimiter::new(RateLimit {
+            qps: 1.0,
+            burst: 3.0,
+            action: RateLimitAction::Refused,
+            max_clients: 16,
+        });
+        let (noisy, quiet) = ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+        let allowed = (0..10).filter(|_| limiter.check(noisy)).count();
+        // The burst, plus at most one token refilled while the loop ran
+        assert!((3..=4).contains(&allowed), "{allowed} allowed");
+        assert!(!limiter.check(noisy));
+        assert!(limiter.check(quiet));
+    }
+}
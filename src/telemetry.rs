@@ -0,0 +1,24 @@
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{Resource, trace::SdkTracerProvider};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+// Spans are only recorded once a subscriber is installed, which happens here and nowhere else, so
+// without OTLP_ENDPOINT the instrumentation in the query path stays disabled. Logging keeps going
+// through `log` either way.
+pub fn init(endpoint: &str) -> anyhow::Result<SdkTracerProvider> {
+    // The endpoint is used as is, so it has to include the /v1/traces path
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name("ndns").build())
+        .build();
+    tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("ndns")))
+        .try_init()?;
+    log::info!("Exporting traces to {endpoint}");
+    Ok(provider)
+}
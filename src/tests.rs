@@ -0,0 +1,1522 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use hickory_client::client::{Client, ClientHandle};
+use hickory_proto::{
+    op::{Message, ResponseCode},
+    rr::{
+        DNSClass, Name, RData, Record, RecordType,
+        rdata::{A, AAAA, CNAME, HTTPS, svcb::SvcParamKey},
+    },
+    runtime::TokioRuntimeProvider,
+    serialize::binary::BinDecodable,
+    udp::UdpClientStream,
+    xfer::{DnsResponse, Protocol},
+};
+use hickory_server::{
+    Server,
+    authority::{MessageRequest, MessageResponse},
+    server::{Request, RequestHandler, ResponseHandler, ResponseInfo},
+};
+use tokio::{net::UdpSocket, task::JoinSet};
+
+use crate::{
+    blocklist::DomainList,
+    config::Configure,
+    dns::{
+        AddressFamily, BlockMode, BlocklistMode, ClientPool, DnsHandler, ForwardZone,
+        HandlerOptions, MalformedPolicy, Upstream, UpstreamStrategy,
+    },
+    local::LocalRecords,
+    metrics::Metrics,
+    ratelimit::{RateLimit, RateLimitAction},
+};
+
+const MOCK_ANSWER: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+const MOCK_PRIVATE: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 10);
+// Priority 1, the root as target, alpn=h2 and a four byte ech
+const MOCK_HTTPS: [u8; 18] = [0, 1, 0, 0, 1, 0, 3, 2, b'h', b'2', 0, 5, 0, 4, 1, 2, 3, 4];
+
+// Echoes the question back with a single A record, written by hand so the mock doesn't share
+// any encoding code with the path under test. The first label picks the behavior: `short` gets a
+// one second TTL, `refused` is refused, `servfail` fails, `silent` is never answered and `flaky`
+// only every other time, `slow`, `popular` and `delayed` are answered late, `https` gets an HTTPS
+// record instead, `private` an address in 192.168/16 and `ecs` the IPv4 address of the query's
+// client subnet, if it had one.
+fn mock_response(request: &[u8], stats: &MockStats) -> Option<Vec<u8>> {
+    let label = mock_label(request)?;
+    if label == b"silent" {
+        return None;
+    }
+    if label == b"flaky" && stats.flaky.fetch_add(1, Ordering::SeqCst) % 2 == 0 {
+        return None;
+    }
+    let ttl: u32 = if label == b"short" { 1 } else { 60 };
+    let mut end = 12;
+    while *request.get(end)? != 0 {
+        end += 1 + request[end] as usize;
+    }
+    // Root label, then type and class
+    end += 5;
+    let mut response = request.get(..end)?.to_vec();
+    response[2] = 0x80 | (request[2] & 0x01);
+    response[3] = 0x80;
+    let error = match label {
+        b"refused" => Some(ResponseCode::Refused),
+        b"servfail" => Some(ResponseCode::ServFail),
+        _ => None,
+    };
+    if let Some(error) = error {
+        response[3] |= error.low();
+        response[6..12].fill(0);
+        return Some(response);
+    }
+    response[6..12].copy_from_slice(&[0, 1, 0, 0, 0, 0]);
+    let address = match label {
+        b"private" => MOCK_PRIVATE.octets(),
+        b"ecs" => mock_subnet(&request[end..]).unwrap_or(MOCK_ANSWER.octets()),
+        _ => MOCK_ANSWER.octets(),
+    };
+    let (record_type, rdata): (u8, &[u8]) = if label == b"https" {
+        (65, &MOCK_HTTPS)
+    } else {
+        (1, &address)
+    };
+    response.extend_from_slice(&[0xc0, 0x0c, 0, record_type, 0, 1]);
+    response.extend_from_slice(&ttl.to_be_bytes());
+    response.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    response.extend_from_slice(rdata);
+    Some(response)
+}
+
+// Expects the OPT record as the only additional record, right after the question
+fn mock_subnet(additional: &[u8]) -> Option<[u8; 4]> {
+    // Root owner name and type 41, followed by class, TTL and RDLENGTH
+    if additional.get(..3)? != [0, 0, 41] {
+        return None;
+    }
+    let mut options = additional.get(11..)?;
+    while let [code_high, code_low, len_high, len_low, rest @ ..] = options {
+        let len = u16::from_be_bytes([*len_high, *len_low]) as usize;
+        let data = rest.get(..len)?;
+        // Family 1 is IPv4, and the address follows the source and scope prefix lengths
+        if [*code_high, *code_low] == [0, 8] && data.get(..2)? == [0, 1] {
+            let mut address = [0; 4];
+            let bytes = data.get(4..)?;
+            address.get_mut(..bytes.len())?.copy_from_slice(bytes);
+            return Some(address);
+        }
+        options = &rest[len..];
+    }
+    None
+}
+
+fn mock_label(request: &[u8]) -> Option<&[u8]> {
+    request.get(13..13 + *request.get(12)? as usize)
+}
+
+// What one mock upstream has seen, so tests running alongside each other can't skew the counts
+#[derive(Default)]
+struct MockStats {
+    // How many `slow` queries are being held at once, and the most there ever were
+    slow_in_flight: AtomicUsize,
+    slow_max_in_flight: AtomicUsize,
+    // `popular` queries are answered late too, and only counted
+    popular: AtomicUsize,
+    // Only the odd `flaky` queries get an answer
+    flaky: AtomicUsize,
+}
+
+async fn spawn_mock_upstream() -> SocketAddr {
+    spawn_counted_mock_upstream().await.0
+}
+
+async fn spawn_counted_mock_upstream() -> (SocketAddr, Arc<MockStats>) {
+    let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let addr = socket.local_addr().unwrap();
+    let stats = Arc::new(MockStats::default());
+    let counted = stats.clone();
+    tokio::spawn(async move {
+        let mut buf = [0; 4096];
+        while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+            let Some(response) = mock_response(&buf[..len], &counted) else {
+                continue;
+            };
+            let label = mock_label(&buf[..len]).unwrap_or_default();
+            let (slow, popular) = (label == b"slow", label == b"popular");
+            let delayed = slow || popular || label == b"delayed";
+            if popular {
+                counted.popular.fetch_add(1, Ordering::SeqCst);
+            }
+            let socket = socket.clone();
+            let stats = counted.clone();
+            tokio::spawn(async move {
+                if slow {
+                    let current = stats.slow_in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    stats
+                        .slow_max_in_flight
+                        .fetch_max(current, Ordering::SeqCst);
+                }
+                if delayed {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+                if slow {
+                    stats.slow_in_flight.fetch_sub(1, Ordering::SeqCst);
+                }
+                let _ = socket.send_to(&response, peer).await;
+            });
+        }
+    });
+    (addr, stats)
+}
+
+async fn connect(addr: SocketAddr) -> Client {
+    let conn = UdpClientStream::builder(addr, TokioRuntimeProvider::new()).build();
+    let (client, background) = Client::connect(conn).await.unwrap();
+    tokio::spawn(background);
+    client
+}
+
+async fn mock_upstream(addr: SocketAddr) -> Upstream {
+    Upstream {
+        addr: addr.to_string(),
+        client: ClientPool::new(connect(addr).await, 1),
+        tcp_fallback: None,
+        trust_anchors: None,
+    }
+}
+
+fn options() -> HandlerOptions {
+    HandlerOptions {
+        cache_max_entries: 100,
+        serve_stale: Duration::ZERO,
+        cache_swr: Duration::ZERO,
+        cache_prefetch: false,
+        decision_cache_max: 100,
+        domain_stats_max: 100,
+        blocked_qtypes: vec![],
+        allowed_clients: vec![],
+        client_families: vec![],
+        rate_limit: None,
+        local_records: LocalRecords::default(),
+        rewrites: LocalRecords::default(),
+        local_ptr_authoritative: false,
+        // The tests resolve names under example., which RFC 6761 handling would answer itself
+        rfc6761_local: false,
+        rfc6761_nxdomain: vec![],
+        forward_ecs: None,
+        block_mode: BlockMode::NxDomain,
+        blocklist_mode: BlocklistMode::Enforce,
+        block_cname_cloaking: false,
+        block_explain: false,
+        default_deny: false,
+        strip_ech: false,
+        rebind_protection: false,
+        rebind_allow: vec![],
+        strict_rd: false,
+        allow_noninet_class: false,
+        malformed_policy: MalformedPolicy::FormErr,
+        edns_cookies: false,
+        max_udp_payload: 1232,
+        max_qname_len: 255,
+        max_labels: 127,
+        ttl_range: None,
+        type_ttl_ranges: Default::default(),
+        sinkhole_ipv4: Ipv4Addr::UNSPECIFIED,
+        sinkhole_ipv6: Ipv6Addr::UNSPECIFIED,
+        block_ttl: 60,
+        upstream_strategy: UpstreamStrategy::Failover,
+        upstream_race: 2,
+        upstream_max_inflight: 256,
+        upstream_minimize: false,
+        log_client_ip: false,
+        dns64_prefix: None,
+        server_version: Some("ndns".to_string()),
+        upstream_timeout: Duration::from_secs(2),
+        upstream_retries: 0,
+        upstream_retry_base: Duration::from_millis(10),
+        response_send_timeout: Duration::from_secs(2),
+    }
+}
+
+// Runs a full server on an ephemeral port; the returned Server has to be kept alive
+async fn spawn_ndns(blocklist: &str, options: HandlerOptions) -> (Server<DnsHandler>, SocketAddr) {
+    spawn_ndns_with_allowlist(blocklist, "", options).await
+}
+
+async fn spawn_ndns_with_allowlist(
+    blocklist: &str,
+    allowlist: &str,
+    options: HandlerOptions,
+) -> (Server<DnsHandler>, SocketAddr) {
+    let upstream = mock_upstream(spawn_mock_upstream().await).await;
+    let mut block = DomainList::default();
+    block.extend_from_str(blocklist);
+    let mut allow = DomainList::default();
+    allow.extend_from_str(allowlist);
+    let handler = DnsHandler::builder(options, Arc::new(Metrics::new().unwrap()))
+        .upstreams(vec![upstream])
+        .blocklist(block)
+        .allowlist(allow)
+        .build();
+    serve(handler).await
+}
+
+async fn spawn_counted_ndns(
+    options: HandlerOptions,
+) -> (Server<DnsHandler>, SocketAddr, Arc<MockStats>) {
+    let (upstream_addr, stats) = spawn_counted_mock_upstream().await;
+    let handler = DnsHandler::builder(options, Arc::new(Metrics::new().unwrap()))
+        .upstreams(vec![mock_upstream(upstream_addr).await])
+        .build();
+    let (server, addr) = serve(handler).await;
+    (server, addr, stats)
+}
+
+async fn serve(handler: DnsHandler) -> (Server<DnsHandler>, SocketAddr) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    (serve_sockets(handler, vec![socket]), addr)
+}
+
+fn serve_sockets(handler: DnsHandler, sockets: Vec<UdpSocket>) -> Server<DnsHandler> {
+    let mut server = Server::new(handler);
+    for socket in sockets {
+        server.register_socket(socket);
+    }
+    server
+}
+
+fn name(name: &str) -> Name {
+    Name::from_ascii(name).unwrap()
+}
+
+// The mock answers every query with a record, so an empty NoError answer means ndns never asked it
+fn assert_not_forwarded(response: &DnsResponse) {
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert!(response.answers().is_empty());
+}
+
+// A bare header and question, so opcodes and flags the client API won't send can be exercised
+fn raw_query(op_code: u8, recursion_desired: bool, name: &str) -> Vec<u8> {
+    let flags = (op_code << 3) | recursion_desired as u8;
+    let mut message = vec![0x12, 0x34, flags, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.extend_from_slice(&[0, 0, 1, 0, 1]);
+    message
+}
+
+#[tokio::test]
+async fn blocked_name_returns_nxdomain() {
+    let (_server, addr) = spawn_ndns("blocked.example", options()).await;
+    let response = connect(addr)
+        .await
+        .query(name("ads.blocked.example."), DNSClass::IN, RecordType::A)
+        .await
+        .unwrap();
+    assert_eq!(response.response_code(), ResponseCode::NXDomain);
+    assert!(response.answers().is_empty());
+    assert!(response.authoritative());
+    assert!(response.recursion_available());
+    assert!(response.recursion_desired());
+}
+
+#[tokio::test]
+async fn allowed_name_is_forwarded() {
+    let (_server, addr) = spawn_ndns("blocked.example", options()).await;
+    let response = connect(addr)
+        .await
+        .query(name("allowed.example."), DNSClass::IN, RecordType::A)
+        .await
+        .unwrap();
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert!(!response.authoritative());
+    assert!(response.recursion_available());
+    let addrs = response
+        .answers()
+        .iter()
+        .filter_map(|record| match record.data() {
+            RData::A(a) => Some(*a),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(addrs, vec![A(MOCK_ANSWER)]);
+}
+
+#[tokio::test]
+async fn refused_block_mode_refuses_only_blocked_names() {
+    let mut options = options();
+    options.block_mode = BlockMode::Refused;
+    let (_server, addr) = spawn_ndns("blocked.example", options).await;
+    let mut client = connect(addr).await;
+    let response = client
+        .query(name("ads.blocked.example."), DNSClass::IN, RecordType::A)
+        .await
+        .unwrap();
+    assert_eq!(response.response_code(), ResponseCode::Refused);
+    assert!(response.answers().is_empty());
+    let response = client
+        .query(name("allowed.example."), DNSClass::IN, RecordType::A)
+        .await
+        .unwrap();
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert_eq!(response.answers().len(), 1);
+}
+
+#[tokio::test]
+async fn local_record_is_authoritative() {
+    let mut options = options();
+    options.local_records = LocalRecords::parse("10.0.0.5 nas.home", true).unwrap();
+    let (_server, addr) = spawn_ndns("", options).await;
+    let response = connect(addr)
+        .await
+        .query(name("nas.home."), DNSClass::IN, RecordType::A)
+        .await
+        .unwrap();
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert!(response.authoritative());
+    assert!(response.recursion_available());
+    assert_eq!(response.answers().len(), 1);
+}
+
+// Returns the raw response so header bits can be checked directly
+async fn exchange_raw(addr: SocketAddr, query: &[u8]) -> Vec<u8> {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.send_to(query, addr).await.unwrap();
+    let mut buf = [0; 512];
+    let len = tokio::time::timeout(Duration::from_secs(2), socket.recv(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(len >= 12);
+    assert_eq!(&buf[..2], &[0x12, 0x34]);
+    buf[..len].to_vec()
+}
+
+#[tokio::test]
+async fn unsupported_opcode_returns_notimp() {
+    let (_server, addr) = spawn_ndns("", options()).await;
+    // NOTIFY
+    let response = exchange_raw(addr, &raw_query(4, true, "allowed.example")).await;
+    assert_eq!(response[3] & 0x0f, ResponseCode::NotImp.low());
+}
+
+#[tokio::test]
+async fn strict_rd_refuses_uncached_non_recursive_query() {
+    let mut options = options();
+    options.strict_rd = true;
+    let (_server, addr) = spawn_ndns("", options).await;
+    let response = exchange_raw(addr, &raw_query(0, false, "allowed.example")).await;
+    assert_eq!(response[3] & 0x0f, ResponseCode::Refused.low());
+    // Once cached through a recursive query, the same name is answered without RD
+    let response = exchange_raw(addr, &raw_query(0, true, "allowed.example")).await;
+    assert_eq!(response[3] & 0x0f, ResponseCode::NoError.low());
+    let response = exchange_raw(addr, &raw_query(0, false, "allowed.example")).await;
+    assert_eq!(response[3] & 0x0f, ResponseCode::NoError.low());
+}
+
+#[tokio::test]
+async fn edns_cookie_gets_server_cookie() {
+    let mut options = options();
+    options.edns_cookies = true;
+    let (_server, addr) = spawn_ndns("", options).await;
+    let client_cookie = [1, 2, 3, 4, 5, 6, 7, 8];
+    let mut query = raw_query(0, true, "allowed.example");
+    // One additional record: an OPT carrying only a client cookie
+    query[11] = 1;
+    query.extend_from_slice(&[0, 0, 41, 0x04, 0xd0, 0, 0, 0, 0, 0, 12, 0, 10, 0, 8]);
+    query.extend_from_slice(&client_cookie);
+    let response = exchange_raw(addr, &query).await;
+    assert_eq!(response[3] & 0x0f, ResponseCode::NoError.low());
+    // The client cookie comes back followed by a 16 byte server cookie
+    let mut expected = vec![0, 10, 0, 24];
+    expected.extend_from_slice(&client_cookie);
+    assert!(
+        response
+            .windows(expected.len())
+            .any(|window| window == expected)
+    );
+}
+
+#[tokio::test]
+async fn short_upstream_ttl_is_raised_to_min_ttl() {
+    let mut options = options();
+    options.ttl_range = Some((300, u32::MAX));
+    let (_server, addr) = spawn_ndns("", options).await;
+    let response = connect(addr)
+        .await
+        .query(name("short.example."), DNSClass::IN, RecordType::A)
+        .await
+        .unwrap();
+    assert_eq!(response.answers().len(), 1);
+    assert_eq!(response.answers()[0].ttl(), 300);
+}
+
+#[tokio::test]
+async fn block_explain_names_matching_entry() {
+    let mut options = options();
+    options.block_explain = true;
+    let (_server, addr) = spawn_ndns("blocked.example", options).await;
+    let response = connect(addr)
+        .await
+        .query(name("ads.blocked.example."), DNSClass::IN, RecordType::A)
+        .await
+        .unwrap();
+    assert_eq!(response.response_code(), ResponseCode::NXDomain);
+    let explanation = response
+        .additionals()
+        .iter()
+        .find_map(|record| match record.data() {
+            RData::TXT(txt) => Some(txt.to_string()),
+            _ => None,
+        })
+        .unwrap();
+    assert!(explanation.contains("blocked.example"));
+}
+
+#[tokio::test]
+async fn local_record_answers_ptr_query() {
+    let mut options = options();
+    options.local_records = LocalRecords::parse("192.168.1.50 nas.home", true).unwrap();
+    options.local_ptr_authoritative = true;
+    let (_server, addr) = spawn_ndns("", options).await;
+    let mut client = connect(addr).await;
+    let response = client
+        .query(
+            name("50.1.168.192.in-addr.arpa."),
+            DNSClass::IN,
+            RecordType::PTR,
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert!(matches!(
+        response.answers()[0].data(),
+        RData::PTR(ptr) if ptr.0 == name("nas.home.")
+    ));
+    // Other private addresses aren't forwarded
+    let response = client
+        .query(
+            name("51.1.168.192.in-addr.arpa."),
+            DNSClass::IN,
+            RecordType::PTR,
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.response_code(), ResponseCode::NXDomain);
+}
+
+#[tokio::test]
+async fn expired_answer_is_served_while_revalidating() {
+    let mut options = options();
+    options.cache_swr = Duration::from_secs(30);
+    let (_server, addr) = spawn_ndns("", options).await;
+    let client = connect(addr).await;
+    let query = || async {
+        client
+            .clone()
+            .query(name("short.example."), DNSClass::IN, RecordType::A)
+            .await
+            .unwrap()
+            .answers()[0]
+            .ttl()
+    };
+    assert_eq!(query().await, 1);
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    // Expired: the old answer comes back at once with the revalidation TTL
+    assert_eq!(query().await, 5);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    // The background refresh has replaced it
+    assert!(query().await <= 1);
+}
+
+#[tokio::test]
+async fn refusing_upstream_returns_refused() {
+    let (_server, addr) = spawn_ndns("", options()).await;
+    let response = exchange_raw(addr, &raw_query(0, true, "refused.example")).await;
+    assert_eq!(response[3] & 0x0f, ResponseCode::Refused.low());
+}
+
+#[tokio::test]
+async fn silent_upstream_returns_servfail() {
+    let mut options = options();
+    options.upstream_timeout = Duration::from_millis(200);
+    let (_server, addr) = spawn_ndns("", options).await;
+    let response = exchange_raw(addr, &raw_query(0, true, "silent.example")).await;
+    assert_eq!(response[3] & 0x0f, ResponseCode::ServFail.low());
+}
+
+#[tokio::test]
+async fn health_probe_fails_on_servfail() {
+    let upstream = mock_upstream(spawn_mock_upstream().await).await;
+    let handler = DnsHandler::builder(options(), Arc::new(Metrics::new().unwrap()))
+        .upstreams(vec![upstream])
+        .build();
+    handler.probe(name("allowed.example.")).await.unwrap();
+    let error = handler.probe(name("servfail.example.")).await.unwrap_err();
+    assert_eq!(error.to_string(), "upstream answered SERVFAIL");
+}
+
+#[tokio::test]
+async fn most_specific_rule_wins_between_lists() {
+    let (_server, addr) = spawn_ndns_with_allowlist(
+        "doubleclick.net\nads.example.com",
+        "safe.doubleclick.net\nexample.com",
+        options(),
+    )
+    .await;
+    let mut client = connect(addr).await;
+    for (query, expected) in [
+        // Allowed inside a blocked parent
+        ("safe.doubleclick.net.", ResponseCode::NoError),
+        ("x.safe.doubleclick.net.", ResponseCode::NoError),
+        ("other.doubleclick.net.", ResponseCode::NXDomain),
+        // Blocked inside an allowed parent
+        ("ads.example.com.", ResponseCode::NXDomain),
+        ("www.example.com.", ResponseCode::NoError),
+    ] {
+        let response = client
+            .query(name(query), DNSClass::IN, RecordType::A)
+            .await
+            .unwrap();
+        assert_eq!(response.response_code(), expected, "{query}");
+    }
+}
+
+#[tokio::test]
+async fn upstream_queries_stay_within_inflight_limit() {
+    let mut options = options();
+    options.upstream_max_inflight = 2;
+    let (_server, addr, stats) = spawn_counted_ndns(options).await;
+    let client = connect(addr).await;
+    let mut queries = JoinSet::new();
+    for i in 0..8 {
+        let mut client = client.clone();
+        queries.spawn(async move {
+            client
+                .query(
+                    name(&format!("slow.{i}.example.")),
+                    DNSClass::IN,
+                    RecordType::A,
+                )
+                .await
+                .unwrap()
+                .response_code()
+        });
+    }
+    while let Some(response_code) = queries.join_next().await {
+        assert_eq!(response_code.unwrap(), ResponseCode::NoError);
+    }
+    let max_in_flight = stats.slow_max_in_flight.load(Ordering::SeqCst);
+    assert!(
+        (1..=2).contains(&max_in_flight),
+        "{max_in_flight} in flight"
+    );
+}
+
+#[tokio::test]
+async fn pooled_upstream_clients_answer_concurrently() {
+    let upstream_addr = spawn_mock_upstream().await;
+    let upstream = Upstream {
+        addr: upstream_addr.to_string(),
+        client: ClientPool::new(connect(upstream_addr).await, 4),
+        tcp_fallback: None,
+        trust_anchors: None,
+    };
+    let handler = DnsHandler::builder(options(), Arc::new(Metrics::new().unwrap()))
+        .upstreams(vec![upstream])
+        .build();
+    let (_server, addr) = serve(handler).await;
+    let client = connect(addr).await;
+    let started = Instant::now();
+    let mut queries = JoinSet::new();
+    for i in 0..64 {
+        let mut client = client.clone();
+        queries.spawn(async move {
+            client
+                .query(
+                    name(&format!("delayed.{i}.example.")),
+                    DNSClass::IN,
+                    RecordType::A,
+                )
+                .await
+                .unwrap()
+                .response_code()
+        });
+    }
+    while let Some(response_code) = queries.join_next().await {
+        assert_eq!(response_code.unwrap(), ResponseCode::NoError);
+    }
+    // Answered one after another, the 100ms upstream delays would add up to 6.4s
+    let elapsed = started.elapsed();
+    assert!(elapsed < Duration::from_secs(2), "took {elapsed:?}");
+}
+
+#[tokio::test]
+async fn concurrent_decisions_stay_consistent() {
+    let mut blocklist = DomainList::default();
+    blocklist.extend_from_str("blocked.example");
+    let upstream = mock_upstream(spawn_mock_upstream().await).await;
+    let handler = DnsHandler::builder(options(), Arc::new(Metrics::new().unwrap()))
+        .upstreams(vec![upstream])
+        .blocklist(blocklist)
+        .build();
+    let (_server, addr) = serve(handler.clone()).await;
+    let client = connect(addr).await;
+    let mut queries = JoinSet::new();
+    for task in 0..16 {
+        let mut client = client.clone();
+        queries.spawn(async move {
+            for i in 0..20 {
+                let k = (task + i) % 5;
+                let (query, expected) = if i % 2 == 0 {
+                    (format!("ads{k}.blocked.example."), ResponseCode::NXDomain)
+                } else {
+                    (format!("site{k}.example."), ResponseCode::NoError)
+                };
+                let response = client
+                    .query(name(&query), DNSClass::IN, RecordType::A)
+                    .await
+                    .unwrap();
+                assert_eq!(response.response_code(), expected, "{query}");
+            }
+        });
+    }
+    while let Some(result) = queries.join_next().await {
+        result.unwrap();
+    }
+    // Every name was decided once and then answered from the caches
+    assert_eq!(handler.decision_cache_sizes(), (5, 5));
+}
+
+#[tokio::test]
+async fn identical_concurrent_misses_share_one_upstream_query() {
+    let (_server, addr, stats) = spawn_counted_ndns(options()).await;
+    let client = connect(addr).await;
+    let mut queries = JoinSet::new();
+    for _ in 0..8 {
+        let mut client = client.clone();
+        queries.spawn(async move {
+            client
+                .query(name("popular.example."), DNSClass::IN, RecordType::A)
+                .await
+                .unwrap()
+                .answers()
+                .len()
+        });
+    }
+    while let Some(answers) = queries.join_next().await {
+        assert_eq!(answers.unwrap(), 1);
+    }
+    assert_eq!(stats.popular.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn chaos_version_query_returns_configured_string() {
+    let mut options = options();
+    options.server_version = Some("ndns test".to_string());
+    let (_server, addr) = spawn_ndns("", options).await;
+    let response = connect(addr)
+        .await
+        .query(name("version.bind."), DNSClass::CH, RecordType::TXT)
+        .await
+        .unwrap();
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert!(matches!(
+        response.answers()[0].data(),
+        RData::TXT(txt) if txt.to_string() == "ndns test"
+    ));
+}
+
+#[tokio::test]
+async fn blocked_query_types_are_answered_without_forwarding() {
+    let mut options = options();
+    options.blocked_qtypes = vec![RecordType::ANY, RecordType::HTTPS];
+    let (_server, addr) = spawn_ndns("", options).await;
+    let mut client = connect(addr).await;
+    let response = client
+        .query(name("allowed.example."), DNSClass::IN, RecordType::ANY)
+        .await
+        .unwrap();
+    assert_eq!(response.response_code(), ResponseCode::NotImp);
+    assert!(response.answers().is_empty());
+    let response = client
+        .query(name("https.example."), DNSClass::IN, RecordType::HTTPS)
+        .await
+        .unwrap();
+    assert_not_forwarded(&response);
+    let response = client
+        .query(name("allowed.example."), DNSClass::IN, RecordType::A)
+        .await
+        .unwrap();
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert_eq!(response.answers()[0].data(), &RData::A(A(MOCK_ANSWER)));
+}
+
+#[tokio::test]
+async fn clients_inside_allowed_ranges_are_answered() {
+    let mut options = options();
+    options.allowed_clients = vec![
+        "10.0.0.0/8".parse().unwrap(),
+        "127.0.0.0/8".parse().unwrap(),
+    ];
+    let (_server, addr) = spawn_ndns("", options).await;
+    let response = connect(addr)
+        .await
+        .query(name("allowed.example."), DNSClass::IN, RecordType::A)
+        .await
+        .unwrap();
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert_eq!(response.answers().len(), 1);
+}
+
+#[tokio::test]
+async fn clients_outside_allowed_ranges_are_refused() {
+    let mut options = options();
+    options.allowed_clients = vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()];
+    let (_server, addr) = spawn_ndns("", options).await;
+    let response = connect(addr)
+        .await
+        .query(name("allowed.example."), DNSClass::IN, RecordType::A)
+        .await
+        .unwrap();
+    assert_eq!(response.response_code(), ResponseCode::Refused);
+    assert!(response.answers().is_empty());
+}
+
+// Carries a client subnet of 198.51.100.77/32
+fn ecs_query(name: &str) -> Vec<u8> {
+    let mut query = raw_query(0, true, name);
+    query[11] = 1;
+    query.extend_from_slice(&[0, 0, 41, 0x04, 0xd0, 0, 0, 0, 0, 0, 12]);
+    query.extend_from_slice(&[0, 8, 0, 8, 0, 1, 32, 0, 198, 51, 100, 77]);
+    query
+}
+
+fn answered_address(response: &[u8]) -> Option<Ipv4Addr> {
+    Message::from_vec(response)
+        .unwrap()
+        .answers()
+        .iter()
+        .find_map(|record| match record.data() {
+            RData::A(a) => Some(a.0),
+            _ => None,
+        })
+}
+
+#[tokio::test]
+async fn client_subnet_is_forwarded_truncated() {
+    let mut options = options();
+    options.forward_ecs = Some((24, 56));
+    let (_server, addr) = spawn_ndns("", options).await;
+    let response = exchange_raw(addr, &ecs_query("ecs.example")).await;
+    assert_eq!(
+        answered_address(&response),
+        Some(Ipv4Addr::new(198, 51, 100, 0))
+    );
+}
+
+#[tokio::test]
+async fn client_subnet_is_stripped_without_forward_ecs() {
+    let (_server, addr) = spawn_ndns("", options()).await;
+    let response = exchange_raw(addr, &ecs_query("ecs.example")).await;
+    assert_eq!(answered_address(&response), Some(MOCK_ANSWER));
+}
+
+#[tokio::test]
+async fn dropped_queries_are_retried() {
+    let mut options = options();
+    options.upstream_timeout = Duration::from_millis(200);
+    options.upstream_retries = 2;
+    let (_server, addr, stats) = spawn_counted_ndns(options).await;
+    let response = exchange_raw(addr, &raw_query(0, true, "flaky.example")).await;
+    assert_eq!(response[3] & 0x0f, ResponseCode::NoError.low());
+    assert_eq!(answered_address(&response), Some(MOCK_ANSWER));
+    // The first attempt was dropped, the retry answered
+    assert_eq!(stats.flaky.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn dropped_queries_fail_without_retries() {
+    let mut options = options();
+    options.upstream_timeout = Duration::from_millis(200);
+    let (_server, addr, stats) = spawn_counted_ndns(options).await;
+    let response = exchange_raw(addr, &raw_query(0, true, "flaky.example")).await;
+    assert_eq!(response[3] & 0x0f, ResponseCode::ServFail.low());
+    assert_eq!(stats.flaky.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn blocking_ignores_case() {
+    let (_server, addr) = spawn_ndns("ADS.example.com", options()).await;
+    for (query, response_code) in [
+        ("ads.example.com", ResponseCode::NXDomain),
+        ("ADS.Example.COM", ResponseCode::NXDomain),
+        ("Tracker.aDs.EXAMPLE.com", ResponseCode::NXDomain),
+        ("Example.COM", ResponseCode::NoError),
+    ] {
+        let response = exchange_raw(addr, &raw_query(0, true, query)).await;
+        assert_eq!(response[3] & 0x0f, response_code.low(), "{query}");
+    }
+}
+
+#[tokio::test]
+async fn unicode_entries_block_their_punycode_queries() {
+    let (_server, addr) = spawn_ndns("bücher.example", options()).await;
+    for (query, response_code) in [
+        ("xn--bcher-kva.example", ResponseCode::NXDomain),
+        ("XN--BCHER-KVA.example", ResponseCode::NXDomain),
+        ("bucher.example", ResponseCode::NoError),
+    ] {
+        let response = exchange_raw(addr, &raw_query(0, true, query)).await;
+        assert_eq!(response[3] & 0x0f, response_code.low(), "{query}");
+    }
+}
+
+#[tokio::test]
+async fn blocked_aaaa_gets_nodata_while_a_forwards() {
+    let mut options = options();
+    options.blocked_qtypes = vec![RecordType::AAAA];
+    let (_server, addr) = spawn_ndns("", options).await;
+    let mut client = connect(addr).await;
+    let response = client
+        .query(name("dual.example."), DNSClass::IN, RecordType::AAAA)
+        .await
+        .unwrap();
+    assert_not_forwarded(&response);
+    let response = client
+        .query(name("dual.example."), DNSClass::IN, RecordType::A)
+        .await
+        .unwrap();
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert_eq!(response.answers()[0].data(), &RData::A(A(MOCK_ANSWER)));
+}
+
+#[tokio::test]
+async fn header_flags_match_who_answered() {
+    let mut options = options();
+    options.local_records = LocalRecords::parse("10.0.0.5 nas.home", true).unwrap();
+    let (_server, addr) = spawn_ndns("blocked.example", options).await;
+    for recursion_desired in [false, true] {
+        for (query, authoritative) in [
+            ("ads.blocked.example", true),
+            ("nas.home", true),
+            ("allowed.example", false),
+        ] {
+            let response = exchange_raw(addr, &raw_query(0, recursion_desired, query)).await;
+            assert_eq!(response[2] & 0x04 != 0, authoritative, "AA for {query}");
+            assert_eq!(response[2] & 0x01 != 0, recursion_desired, "RD for {query}");
+            assert_ne!(response[3] & 0x80, 0, "RA for {query}");
+        }
+    }
+}
+
+#[tokio::test]
+async fn local_cname_targets_are_checked_like_queries() {
+    let mut options = options();
+    options.local_records = LocalRecords::parse(
+        "printer.home CNAME ads.tracker.com\nnas.home CNAME nas.example",
+        true,
+    )
+    .unwrap();
+    options.blocked_qtypes = vec![RecordType::AAAA];
+    let (_server, addr) = spawn_ndns("tracker.com", options).await;
+    let mut client = connect(addr).await;
+    let response = client
+        .query(name("printer.home."), DNSClass::IN, RecordType::A)
+        .await
+        .unwrap();
+    assert_eq!(response.response_code(), ResponseCode::NXDomain);
+    assert!(response.answers().is_empty());
+    let response = client
+        .query(name("nas.home."), DNSClass::IN, RecordType::AAAA)
+        .await
+        .unwrap();
+    assert_not_forwarded(&response);
+    let response = client
+        .query(name("nas.home."), DNSClass::IN, RecordType::A)
+        .await
+        .unwrap();
+    assert_eq!(response.answers().len(), 2);
+    assert_eq!(response.answers()[1].data(), &RData::A(A(MOCK_ANSWER)));
+}
+
+#[tokio::test]
+async fn ipv4_only_client_gets_nodata_for_aaaa() {
+    let mut options = options();
+    options.client_families = vec![("127.0.0.0/8".parse().unwrap(), AddressFamily::Ipv4)];
+    let (_server, addr) = spawn_ndns("", options).await;
+    let mut client = connect(addr).await;
+    let response = client
+        .query(name("dual.example."), DNSClass::IN, RecordType::AAAA)
+        .await
+        .unwrap();
+    assert_not_forwarded(&response);
+    let response = client
+        .query(name("dual.example."), DNSClass::IN, RecordType::A)
+        .await
+        .unwrap();
+    assert_eq!(response.answers()[0].data(), &RData::A(A(MOCK_ANSWER)));
+}
+
+#[tokio::test]
+async fn dns64_synthesizes_aaaa_from_a_records() {
+    let mut options = options();
+    options.dns64_prefix = Some("64:ff9b::/96".parse().unwrap());
+    let (_server, addr) = spawn_ndns("", options).await;
+    let response = connect(addr)
+        .await
+        .query(name("v4only.example."), DNSClass::IN, RecordType::AAAA)
+        .await
+        .unwrap();
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert!(!response.authentic_data());
+    assert_eq!(
+        response.answers()[0].data(),
+        &RData::AAAA(AAAA("64:ff9b::c000:201".parse().unwrap()))
+    );
+}
+
+#[tokio::test]
+async fn rewrite_to_address_overrides_upstream() {
+    let mut options = options();
+    options.rewrites =
+        LocalRecords::from_rewrites(&["time.windows.com=192.168.1.10".to_string()]).unwrap();
+    let (_server, addr) = spawn_ndns("", options).await;
+    let response = connect(addr)
+        .await
+        .query(name("time.windows.com."), DNSClass::IN, RecordType::A)
+        .await
+        .unwrap();
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert_eq!(
+        response.answers()[0].data(),
+        &RData::A(A(Ipv4Addr::new(192, 168, 1, 10)))
+    );
+}
+
+#[tokio::test]
+async fn rewrite_to_name_answers_with_cname() {
+    let mut options = options();
+    options.rewrites =
+        LocalRecords::from_rewrites(&["analytics.vendor.com=sinkhole.example".to_string()])
+            .unwrap();
+    let (_server, addr) = spawn_ndns("", options).await;
+    let response = connect(addr)
+        .await
+        .query(name("analytics.vendor.com."), DNSClass::IN, RecordType::A)
+        .await
+        .unwrap();
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    let answers = response
+        .answers()
+        .iter()
+        .map(|record| record.data().clone())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        answers,
+        vec![
+            RData::CNAME(CNAME(name("sinkhole.example."))),
+            RData::A(A(MOCK_ANSWER)),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn rewrite_targets_are_checked_like_queries() {
+    let mut options = options();
+    options.rewrites = LocalRecords::from_rewrites(&[
+        "analytics.vendor.com=cdn.tracker.net".to_string(),
+        "cdn.vendor.com=cdn.example".to_string(),
+    ])
+    .unwrap();
+    options.strict_rd = true;
+    options.client_families = vec![("127.0.0.0/8".parse().unwrap(), AddressFamily::Ipv4)];
+    let (_server, addr) = spawn_ndns("tracker.net", options).await;
+    let response = exchange_raw(addr, &raw_query(0, true, "analytics.vendor.com")).await;
+    assert_eq!(response[3] & 0x0f, ResponseCode::NXDomain.low());
+    // cdn.example was never resolved, so without RD there is nothing to answer from
+    let response = exchange_raw(addr, &raw_query(0, false, "cdn.vendor.com")).await;
+    assert_eq!(response[3] & 0x0f, ResponseCode::Refused.low());
+    let response = connect(addr)
+        .await
+        .query(name("cdn.vendor.com."), DNSClass::IN, RecordType::AAAA)
+        .await
+        .unwrap();
+    assert_not_forwarded(&response);
+}
+
+#[tokio::test]
+async fn udp_edns_payload_is_clamped() {
+    let (_server, addr) = spawn_ndns("", options()).await;
+    let mut query = raw_query(0, true, "allowed.example");
+    // One additional record: an OPT advertising a 4096 byte payload
+    query[11] = 1;
+    query.extend_from_slice(&[0, 0, 41, 0x10, 0x00, 0, 0, 0, 0, 0, 0]);
+    let response = exchange_raw(addr, &query).await;
+    assert_eq!(response[3] & 0x0f, ResponseCode::NoError.low());
+    // The OPT record in the answer advertises 1232 instead
+    assert!(
+        response
+            .windows(5)
+            .any(|window| window == [0, 0, 41, 0x04, 0xd0])
+    );
+}
+
+#[tokio::test]
+async fn default_deny_only_forwards_allowlisted_names() {
+    let mut options = options();
+    options.default_deny = true;
+    let (_server, addr) = spawn_ndns_with_allowlist("", "allowed.example", options).await;
+    let mut client = connect(addr).await;
+    let response = client
+        .query(name("www.allowed.example."), DNSClass::IN, RecordType::A)
+        .await
+        .unwrap();
+    assert_eq!(response.answers()[0].data(), &RData::A(A(MOCK_ANSWER)));
+    let response = client
+        .query(name("other.example."), DNSClass::IN, RecordType::A)
+        .await
+        .unwrap();
+    assert_eq!(response.response_code(), ResponseCode::NXDomain);
+    assert!(response.answers().is_empty());
+}
+
+#[tokio::test]
+async fn strip_ech_removes_only_the_ech_param() {
+    let mut options = options();
+    options.strip_ech = true;
+    let (_server, addr) = spawn_ndns("", options).await;
+    let response = connect(addr)
+        .await
+        .query(name("https.example."), DNSClass::IN, RecordType::HTTPS)
+        .await
+        .unwrap();
+    let RData::HTTPS(HTTPS(svcb)) = response.answers()[0].data() else {
+        panic!("expected an HTTPS record");
+    };
+    let keys = svcb
+        .svc_params()
+        .iter()
+        .map(|(key, _)| *key)
+        .collect::<Vec<_>>();
+    assert_eq!(keys, vec![SvcParamKey::Alpn]);
+}
+
+#[tokio::test]
+async fn non_internet_class_is_refused() {
+    let (_server, addr) = spawn_ndns("", options()).await;
+    let mut client = connect(addr).await;
+    let response = client
+        .query(name("allowed.example."), DNSClass::IN, RecordType::A)
+        .await
+        .unwrap();
+    assert_eq!(response.answers()[0].data(), &RData::A(A(MOCK_ANSWER)));
+    let response = client
+        .query(name("allowed.example."), DNSClass::HS, RecordType::A)
+        .await
+        .unwrap();
+    assert_eq!(response.response_code(), ResponseCode::Refused);
+}
+
+#[tokio::test]
+async fn replaced_upstream_client_takes_over_from_a_dead_one() {
+    let upstream_addr = spawn_mock_upstream().await;
+    let conn = UdpClientStream::builder(upstream_addr, TokioRuntimeProvider::new()).build();
+    // Without its background task the client can't send anything, as after the task has ended
+    let (dead, background) = Client::connect(conn).await.unwrap();
+    drop(background);
+    let upstream = Upstream {
+        addr: upstream_addr.to_string(),
+        client: ClientPool::new(dead, 1),
+        tcp_fallback: None,
+        trust_anchors: None,
+    };
+    let handler = DnsHandler::builder(options(), Arc::new(Metrics::new().unwrap()))
+        .upstreams(vec![upstream])
+        .build();
+    let (_server, addr) = serve(handler.clone()).await;
+    let mut client = connect(addr).await;
+
+    let response = client
+        .query(name("allowed.example."), DNSClass::IN, RecordType::A)
+        .await
+        .unwrap();
+    assert_eq!(response.response_code(), ResponseCode::ServFail);
+
+    handler
+        .replace_upstream_client(
+            None,
+            &upstream_addr.to_string(),
+            false,
+            connect(upstream_addr).await,
+        )
+        .await;
+    let response = client
+        .query(name("allowed.example."), DNSClass::IN, RecordType::A)
+        .await
+        .unwrap();
+    assert_eq!(response.answers()[0].data(), &RData::A(A(MOCK_ANSWER)));
+}
+
+#[tokio::test]
+async fn every_udp_bind_address_is_registered() {
+    let addrs = ["127.0.0.1:0".to_string(), "127.0.0.1:0".to_string()];
+    let sockets = Configure::bind_udp_sockets("UDP", &addrs).await.unwrap();
+    let bound = sockets
+        .iter()
+        .map(|socket| socket.local_addr().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(bound.len(), 2);
+    let upstream = mock_upstream(spawn_mock_upstream().await).await;
+    let handler = DnsHandler::builder(options(), Arc::new(Metrics::new().unwrap()))
+        .upstreams(vec![upstream])
+        .build();
+    let _server = serve_sockets(handler, sockets);
+    for addr in bound {
+        let mut client = connect(addr).await;
+        let response = client
+            .query(name("allowed.example."), DNSClass::IN, RecordType::A)
+            .await
+            .unwrap();
+        assert_eq!(response.answers()[0].data(), &RData::A(A(MOCK_ANSWER)));
+    }
+}
+
+// Two questions in one message, which decodes fine but isn't a query ndns can answer
+fn two_question_query() -> Vec<u8> {
+    let mut message = raw_query(0, true, "allowed.example");
+    message[5] = 2;
+    message.extend_from_within(12..);
+    message
+}
+
+#[tokio::test]
+async fn malformed_request_gets_formerr_by_default() {
+    let (_server, addr) = spawn_ndns("", options()).await;
+    let response = exchange_raw(addr, &two_question_query()).await;
+    assert_eq!(response[3] & 0x0f, ResponseCode::FormErr.low());
+}
+
+#[tokio::test]
+async fn malformed_policy_servfail_answers_servfail() {
+    let mut options = options();
+    options.malformed_policy = MalformedPolicy::ServFail;
+    let (_server, addr) = spawn_ndns("", options).await;
+    let response = exchange_raw(addr, &two_question_query()).await;
+    assert_eq!(response[3] & 0x0f, ResponseCode::ServFail.low());
+}
+
+#[tokio::test]
+async fn denied_clients_get_refused_for_malformed_requests() {
+    let mut options = options();
+    options.allowed_clients = vec!["10.0.0.0/8".parse().unwrap()];
+    let (_server, addr) = spawn_ndns("", options).await;
+    let response = exchange_raw(addr, &two_question_query()).await;
+    assert_eq!(response[3] & 0x0f, ResponseCode::Refused.low());
+}
+
+#[tokio::test]
+async fn malformed_requests_use_up_the_rate_limit() {
+    let mut options = options();
+    options.rate_limit = Some(RateLimit {
+        qps: 0.01,
+        burst: 2.0,
+        action: RateLimitAction::Refused,
+        max_clients: 16,
+    });
+    let (_server, addr) = spawn_ndns("", options).await;
+    for _ in 0..2 {
+        let response = exchange_raw(addr, &two_question_query()).await;
+        assert_eq!(response[3] & 0x0f, ResponseCode::FormErr.low());
+    }
+    let response = exchange_raw(addr, &two_question_query()).await;
+    assert_eq!(response[3] & 0x0f, ResponseCode::Refused.low());
+    let response = exchange_raw(addr, &raw_query(0, true, "allowed.example")).await;
+    assert_eq!(response[3] & 0x0f, ResponseCode::Refused.low());
+}
+
+#[tokio::test]
+async fn malformed_policy_drop_sends_nothing() {
+    let mut options = options();
+    options.malformed_policy = MalformedPolicy::Drop;
+    let (_server, addr) = spawn_ndns("", options).await;
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.send_to(&two_question_query(), addr).await.unwrap();
+    let mut buf = [0; 512];
+    let received = tokio::time::timeout(Duration::from_millis(500), socket.recv(&mut buf)).await;
+    assert!(received.is_err());
+    // A well-formed query still gets its answer
+    let response = exchange_raw(addr, &raw_query(0, true, "allowed.example")).await;
+    assert_eq!(response[3] & 0x0f, ResponseCode::NoError.low());
+}
+
+#[tokio::test]
+async fn type_ttl_range_overrides_the_general_one() {
+    let mut options = options();
+    options.ttl_range = Some((10, 30));
+    options.type_ttl_ranges = [(RecordType::A, (120, 3600))].into_iter().collect();
+    let (_server, addr) = spawn_ndns("", options).await;
+    let response = connect(addr)
+        .await
+        .query(name("short.example."), DNSClass::IN, RecordType::A)
+        .await
+        .unwrap();
+    assert_eq!(response.answers()[0].ttl(), 120);
+}
+
+fn rfc6761_options() -> HandlerOptions {
+    let mut options = options();
+    options.rfc6761_local = true;
+    options.rfc6761_nxdomain = vec![name("invalid."), name("test."), name("example.")];
+    options
+}
+
+#[tokio::test]
+async fn localhost_is_answered_with_loopback() {
+    let (_server, addr) = spawn_ndns("", rfc6761_options()).await;
+    let mut client = connect(addr).await;
+    let response = client
+        .query(name("localhost."), DNSClass::IN, RecordType::A)
+        .await
+        .unwrap();
+    assert!(response.authoritative());
+    assert_eq!(
+        response.answers()[0].data(),
+        &RData::A(A(Ipv4Addr::LOCALHOST))
+    );
+    let response = client
+        .query(name("app.localhost."), DNSClass::IN, RecordType::AAAA)
+        .await
+        .unwrap();
+    assert_eq!(
+        response.answers()[0].data(),
+        &RData::AAAA(AAAA(Ipv6Addr::LOCALHOST))
+    );
+    let response = client
+        .query(name("localhost."), DNSClass::IN, RecordType::MX)
+        .await
+        .unwrap();
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert!(response.answers().is_empty());
+}
+
+#[tokio::test]
+async fn loopback_reverse_names_point_at_localhost() {
+    let (_server, addr) = spawn_ndns("", rfc6761_options()).await;
+    let mut client = connect(addr).await;
+    for reverse in [
+        "1.0.0.127.in-addr.arpa.",
+        "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.ip6.arpa.",
+    ] {
+        let response = client
+            .query(name(reverse), DNSClass::IN, RecordType::PTR)
+            .await
+            .unwrap();
+        assert!(matches!(
+            response.answers()[0].data(),
+            RData::PTR(ptr) if ptr.0 == name("localhost.")
+        ));
+    }
+}
+
+#[tokio::test]
+async fn special_use_zones_are_nxdomain() {
+    let (_server, addr) = spawn_ndns("", rfc6761_options()).await;
+    let mut client = connect(addr).await;
+    for special in ["host.invalid.", "allowed.test.", "allowed.example."] {
+        let response = client
+            .query(name(special), DNSClass::IN, RecordType::A)
+            .await
+            .unwrap();
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+        assert!(response.answers().is_empty());
+    }
+    // Anything else still goes upstream
+    let response = client
+        .query(name("allowed.org."), DNSClass::IN, RecordType::A)
+        .await
+        .unwrap();
+    assert_eq!(response.answers()[0].data(), &RData::A(A(MOCK_ANSWER)));
+}
+
+#[tokio::test]
+async fn over_long_names_are_rejected_with_formerr() {
+    let mut options = options();
+    options.max_qname_len = 40;
+    options.max_labels = 4;
+    let (_server, addr) = spawn_ndns("", options).await;
+    let mut client = connect(addr).await;
+    for long in [
+        "aGVsbG8gdGhpcyBpcyBhIHR1bm5lbA.allowed.example.",
+        "a.b.c.allowed.example.",
+    ] {
+        let response = client
+            .query(name(long), DNSClass::IN, RecordType::A)
+            .await
+            .unwrap();
+        assert_eq!(response.response_code(), ResponseCode::FormErr);
+    }
+    let response = client
+        .query(name("www.allowed.example."), DNSClass::IN, RecordType::A)
+        .await
+        .unwrap();
+    assert_eq!(response.answers()[0].data(), &RData::A(A(MOCK_ANSWER)));
+}
+
+#[tokio::test]
+async fn denied_clients_get_refused_for_over_long_names() {
+    let mut options = options();
+    options.max_qname_len = 40;
+    options.allowed_clients = vec!["10.0.0.0/8".parse().unwrap()];
+    let (_server, addr) = spawn_ndns("", options).await;
+    let query = raw_query(0, true, "aGVsbG8gdGhpcyBpcyBhIHR1bm5lbA.allowed.example");
+    let response = exchange_raw(addr, &query).await;
+    assert_eq!(response[3] & 0x0f, ResponseCode::Refused.low());
+}
+
+#[tokio::test]
+async fn over_long_names_use_up_the_rate_limit() {
+    let mut options = options();
+    options.max_qname_len = 40;
+    options.rate_limit = Some(RateLimit {
+        qps: 0.01,
+        burst: 1.0,
+        action: RateLimitAction::Refused,
+        max_clients: 16,
+    });
+    let (_server, addr) = spawn_ndns("", options).await;
+    let query = raw_query(0, true, "aGVsbG8gdGhpcyBpcyBhIHR1bm5lbA.allowed.example");
+    let response = exchange_raw(addr, &query).await;
+    assert_eq!(response[3] & 0x0f, ResponseCode::FormErr.low());
+    let response = exchange_raw(addr, &query).await;
+    assert_eq!(response[3] & 0x0f, ResponseCode::Refused.low());
+}
+
+#[tokio::test]
+async fn type_scoped_block_leaves_other_types_resolving() {
+    let (_server, addr) = spawn_ndns("facebook.com HTTPS", options()).await;
+    let mut client = connect(addr).await;
+    for (query_type, expected) in [
+        (RecordType::HTTPS, ResponseCode::NXDomain),
+        (RecordType::A, ResponseCode::NoError),
+        // The second HTTPS query is answered from the decision cache
+        (RecordType::HTTPS, ResponseCode::NXDomain),
+        (RecordType::AAAA, ResponseCode::NoError),
+    ] {
+        let response = client
+            .query(name("facebook.com."), DNSClass::IN, query_type)
+            .await
+            .unwrap();
+        assert_eq!(response.response_code(), expected, "{query_type}");
+    }
+}
+
+#[tokio::test]
+async fn decision_cache_round_trips_and_drops_stale_entries() {
+    let handler_with = |blocklist: &str| {
+        let mut list = DomainList::default();
+        list.extend_from_str(blocklist);
+        DnsHandler::builder(options(), Arc::new(Metrics::new().unwrap()))
+            .blocklist(list)
+            .build()
+    };
+    let handler = handler_with("ads.example\ntracker.example");
+    let (_server, addr) = serve(handler.clone()).await;
+    let mut client = connect(addr).await;
+    for blocked in ["ads.example.", "tracker.example."] {
+        let response = client
+            .query(name(blocked), DNSClass::IN, RecordType::A)
+            .await
+            .unwrap();
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+    }
+    let snapshot = handler.decision_snapshot().unwrap();
+
+    // tracker.example was dropped from the list while ndns was down
+    let restored = handler_with("ads.example");
+    assert_eq!(restored.restore_decisions(&snapshot).await.unwrap(), (1, 1));
+    assert_eq!(restored.decision_cache_sizes(), (0, 1));
+}
+
+#[tokio::test]
+async fn rebind_protection_scrubs_private_answers_for_public_names() {
+    let mut options = options();
+    options.rebind_protection = true;
+    options.rebind_allow = vec![name("nas.example.")];
+    let upstream_addr = spawn_mock_upstream().await;
+    let handler = DnsHandler::builder(options, Arc::new(Metrics::new().unwrap()))
+        .upstreams(vec![mock_upstream(upstream_addr).await])
+        .forward_zones(vec![ForwardZone {
+            zone: name("home.example."),
+            upstreams: vec![mock_upstream(upstream_addr).await],
+        }])
+        .build();
+    let (_server, addr) = serve(handler).await;
+    let mut client = connect(addr).await;
+
+    let response = client
+        .query(name("private.example."), DNSClass::IN, RecordType::A)
+        .await
+        .unwrap();
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert!(response.answers().is_empty());
+    for kept in ["private.home.example.", "private.nas.example."] {
+        let response = client
+            .query(name(kept), DNSClass::IN, RecordType::A)
+            .await
+            .unwrap();
+        assert_eq!(
+            response.answers()[0].data(),
+            &RData::A(A(MOCK_PRIVATE)),
+            "{kept}"
+        );
+    }
+}
+
+// Never finishes a send, like a stream whose peer stopped reading
+#[derive(Clone)]
+struct StalledResponseHandler;
+
+#[async_trait::async_trait]
+impl ResponseHandler for StalledResponseHandler {
+    async fn send_response<'a>(
+        &mut self,
+        _response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> std::io::Result<ResponseInfo> {
+        std::future::pending().await
+    }
+}
+
+#[tokio::test]
+async fn stalled_response_send_is_abandoned() {
+    let mut options = options();
+    options.local_records = LocalRecords::parse("192.0.2.7 stalled.home", false).unwrap();
+    options.response_send_timeout = Duration::from_millis(100);
+    let handler = DnsHandler::builder(options, Arc::new(Metrics::new().unwrap())).build();
+    let message = MessageRequest::from_bytes(&raw_query(0, true, "stalled.home")).unwrap();
+    let request = Request::new(message, "127.0.0.1:5300".parse().unwrap(), Protocol::Tcp);
+    let info = tokio::time::timeout(
+        Duration::from_secs(2),
+        handler.handle_request(&request, StalledResponseHandler),
+    )
+    .await
+    .expect("the stalled send should have been abandoned");
+    assert_eq!(info.response_code(), ResponseCode::ServFail);
+}